@@ -1,4 +1,5 @@
 
+import sys
 import matplotlib.pyplot as plt
 
 times = []
@@ -8,7 +9,9 @@ spawn_metrics = []
 ns_to_sec    = 1000000000
 ns_to_millis = 1000000
 
-with open("metrics", "rt") as f:
+metrics_path = sys.argv[1] if len(sys.argv) > 1 else "metrics"
+
+with open(metrics_path, "rt") as f:
     for line in f.readlines():
         tokens = line.split("\t")
         if tokens[0] == "summary_timeline":
@@ -0,0 +1,511 @@
+//! Rescaling KPIs computed from a benchmark metrics file (see `metrics`).
+
+use crate::metrics::MetricsFile;
+use crate::placement::{Traffic, Volume};
+
+/// Percentile columns of a `summary_timeline` record, in file order.
+pub const PERCENTILES: [&str; 7] = ["p25", "p50", "p75", "p95", "p99", "p99.9", "max"];
+
+/// Latency percentiles (ns) of one timeline bucket.
+#[derive(Debug, Clone)]
+pub struct LatencySample {
+    pub time: u64,
+    pub percentiles: Vec<u64>,
+}
+
+/// A rescaling operation: the new process joined at `bootstrap` and bins were moved at `moved`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RescaleEvent {
+    pub bootstrap: u64,
+    pub moved: u64,
+}
+
+/// Number of records processed in the bucket starting at `time`.
+#[derive(Debug, Clone, Copy)]
+pub struct ThroughputSample {
+    pub time: u64,
+    pub records: u64,
+}
+
+/// Records processed by `worker` in the bucket starting at `time`, with their mean and max latency (ns).
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerSample {
+    pub worker: usize,
+    pub time: u64,
+    pub records: u64,
+    pub mean_latency: u64,
+    pub max_latency: u64,
+}
+
+/// A bootstrap protocol phase completed by `process` at `time` (ns since the start of the run).
+#[derive(Debug, Clone)]
+pub struct BootstrapPhase {
+    pub process: usize,
+    pub phase: String,
+    pub time: u64,
+}
+
+/// Size of the progress state and number of pending configurations of `worker` at `time`.
+#[derive(Debug, Clone, Copy)]
+pub struct StateSample {
+    pub worker: usize,
+    pub time: u64,
+    pub progress_entries: u64,
+    pub progress_updates: u64,
+    pub pending_controls: u64,
+}
+
+/// A spawned process that exited unsuccessfully, or had to be killed at the end of the run.
+#[derive(Debug, Clone)]
+pub struct ProcessFailure {
+    pub process: usize,
+    pub status: String,
+}
+
+/// A spawn attempt of `process` started at `time` that did not join the cluster.
+#[derive(Debug, Clone)]
+pub struct SpawnFailure {
+    pub process: usize,
+    pub attempt: usize,
+    pub time: u64,
+    pub reason: String,
+}
+
+/// The bootstrap server `server` (a worker index) chosen for the joining `process`.
+#[derive(Debug, Clone, Copy)]
+pub struct JoinServer {
+    pub process: usize,
+    pub server: usize,
+}
+
+/// A wave of bin moves issued at `time`, moving `bins` bins with an estimated `bytes` of state.
+#[derive(Debug, Clone, Copy)]
+pub struct MigrationWave {
+    pub time: u64,
+    pub bins: u64,
+    pub bytes: u64,
+}
+
+/// A migration plan rolled back at `time` with `moves` compensating moves, applied at `applied`
+/// (`None` if the compensating configuration was not applied before the input ended).
+#[derive(Debug, Clone, Copy)]
+pub struct Rollback {
+    pub time: u64,
+    pub moves: u64,
+    pub applied: Option<u64>,
+}
+
+/// A migration plan still open when the input ended at `time`: `issued` bins were moved by the
+/// waves issued so far, and `remaining` waves were not issued.
+#[derive(Debug, Clone, Copy)]
+pub struct UncommittedPlan {
+    pub time: u64,
+    pub issued: u64,
+    pub remaining: u64,
+}
+
+/// The traffic of the migration plan computed at `time`.
+#[derive(Debug, Clone, Copy)]
+pub struct PlanTraffic {
+    pub time: u64,
+    pub traffic: Traffic,
+}
+
+/// A status request issued at `time`: the bins whose worker was observed, the ones that moved while
+/// it was collected and the bins the controller's assignment disagreed on (adopted if `reconciled`).
+#[derive(Debug, Clone, Copy)]
+pub struct BinStatus {
+    pub time: u64,
+    pub observed: u64,
+    pub conflicts: u64,
+    pub differences: u64,
+    pub reconciled: bool,
+}
+
+/// Phases of one process as `(phase, time, time since the previous phase)`.
+pub type PhaseBreakdown<'a> = Vec<(&'a str, u64, u64)>;
+
+#[derive(Debug, Default)]
+pub struct Run {
+    pub latency: Vec<LatencySample>,
+    pub events: Vec<RescaleEvent>,
+    pub throughput: Vec<ThroughputSample>,
+    pub workers: Vec<WorkerSample>,
+    /// Bootstrap phases, in file order (grouped by process, in protocol order).
+    pub phases: Vec<BootstrapPhase>,
+    pub state: Vec<StateSample>,
+    pub failures: Vec<ProcessFailure>,
+    pub spawn_failures: Vec<SpawnFailure>,
+    pub join_servers: Vec<JoinServer>,
+    pub waves: Vec<MigrationWave>,
+    pub rollbacks: Vec<Rollback>,
+    pub uncommitted: Vec<UncommittedPlan>,
+    pub statuses: Vec<BinStatus>,
+    pub traffic: Vec<PlanTraffic>,
+}
+
+impl Run {
+    pub fn from_metrics(metrics: &MetricsFile) -> Result<Self, String> {
+        let parse = |field: &String| field.parse::<u64>().map_err(|e| format!("invalid field {:?}: {}", field, e));
+
+        let mut run = Run::default();
+        for fields in metrics.records("summary_timeline") {
+            if fields.len() < 1 + PERCENTILES.len() {
+                return Err(format!("short summary_timeline record: {:?}", fields));
+            }
+            run.latency.push(LatencySample {
+                time: parse(&fields[0])?,
+                percentiles: fields[1..=PERCENTILES.len()].iter().map(parse).collect::<Result<_, _>>()?,
+            });
+        }
+        for fields in metrics.records("spawn_metric") {
+            if fields.len() < 2 {
+                return Err(format!("short spawn_metric record: {:?}", fields));
+            }
+            run.events.push(RescaleEvent { bootstrap: parse(&fields[0])?, moved: parse(&fields[1])? });
+        }
+        for fields in metrics.records("throughput") {
+            if fields.len() < 2 {
+                return Err(format!("short throughput record: {:?}", fields));
+            }
+            run.throughput.push(ThroughputSample { time: parse(&fields[0])?, records: parse(&fields[1])? });
+        }
+        for fields in metrics.records("worker_timeline") {
+            if fields.len() < 5 {
+                return Err(format!("short worker_timeline record: {:?}", fields));
+            }
+            run.workers.push(WorkerSample {
+                worker: parse(&fields[0])? as usize,
+                time: parse(&fields[1])?,
+                records: parse(&fields[2])?,
+                mean_latency: parse(&fields[3])?,
+                max_latency: parse(&fields[4])?,
+            });
+        }
+        for fields in metrics.records("bootstrap_phase") {
+            if fields.len() < 3 {
+                return Err(format!("short bootstrap_phase record: {:?}", fields));
+            }
+            run.phases.push(BootstrapPhase { process: parse(&fields[0])? as usize, phase: fields[1].clone(), time: parse(&fields[2])? });
+        }
+        for fields in metrics.records("state_size") {
+            if fields.len() < 5 {
+                return Err(format!("short state_size record: {:?}", fields));
+            }
+            run.state.push(StateSample {
+                worker: parse(&fields[0])? as usize,
+                time: parse(&fields[1])?,
+                progress_entries: parse(&fields[2])?,
+                progress_updates: parse(&fields[3])?,
+                pending_controls: parse(&fields[4])?,
+            });
+        }
+        for fields in metrics.records("process_failure") {
+            if fields.len() < 2 {
+                return Err(format!("short process_failure record: {:?}", fields));
+            }
+            run.failures.push(ProcessFailure { process: parse(&fields[0])? as usize, status: fields[1].clone() });
+        }
+        for fields in metrics.records("spawn_failure") {
+            if fields.len() < 4 {
+                return Err(format!("short spawn_failure record: {:?}", fields));
+            }
+            run.spawn_failures.push(SpawnFailure {
+                process: parse(&fields[0])? as usize,
+                attempt: parse(&fields[1])? as usize,
+                time: parse(&fields[2])?,
+                reason: fields[3].clone(),
+            });
+        }
+        for fields in metrics.records("join_server") {
+            if fields.len() < 2 {
+                return Err(format!("short join_server record: {:?}", fields));
+            }
+            run.join_servers.push(JoinServer { process: parse(&fields[0])? as usize, server: parse(&fields[1])? as usize });
+        }
+        for fields in metrics.records("migration_wave") {
+            if fields.len() < 3 {
+                return Err(format!("short migration_wave record: {:?}", fields));
+            }
+            run.waves.push(MigrationWave { time: parse(&fields[0])?, bins: parse(&fields[1])?, bytes: parse(&fields[2])? });
+        }
+        for fields in metrics.records("migration_rollback") {
+            if fields.len() < 2 {
+                return Err(format!("short migration_rollback record: {:?}", fields));
+            }
+            run.rollbacks.push(Rollback { time: parse(&fields[0])?, moves: parse(&fields[1])?, applied: None });
+        }
+        for fields in metrics.records("rollback_applied") {
+            if fields.len() < 2 {
+                return Err(format!("short rollback_applied record: {:?}", fields));
+            }
+            let time = parse(&fields[0])?;
+            let rollback = run.rollbacks.iter_mut().find(|r| r.time == time)
+                .ok_or_else(|| format!("rollback_applied record without rollback: {:?}", fields))?;
+            rollback.applied = Some(parse(&fields[1])?);
+        }
+        for fields in metrics.records("migration_uncommitted") {
+            if fields.len() < 3 {
+                return Err(format!("short migration_uncommitted record: {:?}", fields));
+            }
+            run.uncommitted.push(UncommittedPlan { time: parse(&fields[0])?, issued: parse(&fields[1])?, remaining: parse(&fields[2])? });
+        }
+        for fields in metrics.records("bin_status") {
+            if fields.len() < 5 {
+                return Err(format!("short bin_status record: {:?}", fields));
+            }
+            run.statuses.push(BinStatus {
+                time: parse(&fields[0])?,
+                observed: parse(&fields[1])?,
+                conflicts: parse(&fields[2])?,
+                differences: parse(&fields[3])?,
+                reconciled: parse(&fields[4])? != 0,
+            });
+        }
+        for fields in metrics.records("plan_traffic") {
+            if fields.len() < 7 {
+                return Err(format!("short plan_traffic record: {:?}", fields));
+            }
+            let volume = |i: usize| -> Result<Volume, String> { Ok(Volume { bins: parse(&fields[i])? as usize, bytes: parse(&fields[i + 1])? }) };
+            run.traffic.push(PlanTraffic {
+                time: parse(&fields[0])?,
+                traffic: Traffic { intra_process: volume(1)?, cross_process: volume(3)?, cross_host: volume(5)? },
+            });
+        }
+        run.latency.sort_by_key(|s| s.time);
+        run.events.sort_by_key(|e| e.bootstrap);
+        run.throughput.sort_by_key(|s| s.time);
+        run.workers.sort_by_key(|s| (s.worker, s.time));
+        run.state.sort_by_key(|s| (s.worker, s.time));
+        run.waves.sort_by_key(|w| w.time);
+        Ok(run)
+    }
+}
+
+/// Parameters of the KPI computation.
+#[derive(Debug, Clone, Copy)]
+pub struct KpiConfig {
+    /// Index into `PERCENTILES` of the latency percentile to analyze.
+    pub percentile: usize,
+    /// Length of the windows before and after a rescale used as baseline and steady state.
+    pub window_ns: u64,
+    /// Latency is back to baseline when within `baseline * (1 + tolerance)`.
+    pub tolerance: f64,
+    /// Number of consecutive samples that must be back to baseline to consider latency recovered.
+    pub stable_samples: usize,
+}
+
+impl Default for KpiConfig {
+    fn default() -> Self {
+        KpiConfig { percentile: 4, window_ns: 2_000_000_000, tolerance: 0.1, stable_samples: 4 }
+    }
+}
+
+/// KPIs of a single rescaling operation. Latencies are in ns, throughputs in records/s.
+#[derive(Debug, Clone)]
+pub struct RescaleKpis {
+    pub event: RescaleEvent,
+    pub before: Option<f64>,
+    pub during: Option<f64>,
+    pub after: Option<f64>,
+    pub peak: Option<(u64, u64)>,
+    /// Time at which latency returned to baseline, `None` if it never did.
+    pub recovered_at: Option<u64>,
+    pub throughput_before: Option<f64>,
+    pub throughput_after: Option<f64>,
+    /// Per-worker throughput before and after the rescale, as `(worker, before, after)`.
+    pub workers: Vec<(usize, Option<f64>, Option<f64>)>,
+    /// Migration waves of the rescale, empty for runs without `migration_wave` records.
+    pub waves: Vec<MigrationWave>,
+    /// Set if the migration plan of the rescale was rolled back.
+    pub rollback: Option<Rollback>,
+    /// Traffic of the migration plan, `None` for runs without `plan_traffic` records.
+    pub traffic: Option<Traffic>,
+}
+
+impl RescaleKpis {
+    /// Time from the bootstrap of the new process until latency returned to baseline.
+    pub fn recovery_ns(&self) -> Option<u64> {
+        self.recovered_at.map(|t| t.saturating_sub(self.event.bootstrap))
+    }
+
+    /// Relative throughput change after the rescale, e.g. `0.5` for +50%.
+    pub fn throughput_change(&self) -> Option<f64> {
+        match (self.throughput_before, self.throughput_after) {
+            (Some(before), Some(after)) if before > 0. => Some(after / before - 1.),
+            _ => None,
+        }
+    }
+}
+
+fn mean<I: Iterator<Item=u64>>(values: I) -> Option<f64> {
+    let (sum, count) = values.fold((0_f64, 0_usize), |(s, c), v| (s + v as f64, c + 1));
+    if count == 0 { None } else { Some(sum / count as f64) }
+}
+
+impl Run {
+    fn latency_in(&self, percentile: usize, from: u64, to: u64) -> impl Iterator<Item=&LatencySample> {
+        self.latency.iter().filter(move |s| s.time >= from && s.time < to).filter(move |s| s.percentiles.len() > percentile)
+    }
+
+    /// Mean throughput (records/s) of the buckets in `[from, to)`.
+    fn throughput_in(&self, from: u64, to: u64) -> Option<f64> {
+        let samples = self.throughput.iter().filter(|s| s.time >= from && s.time < to).collect::<Vec<_>>();
+        if samples.len() < 2 {
+            return None;
+        }
+        let span = samples.last().unwrap().time - samples[0].time;
+        let records: u64 = samples[..samples.len() - 1].iter().map(|s| s.records).sum();
+        if span == 0 { None } else { Some(records as f64 * 1_000_000_000. / span as f64) }
+    }
+
+    /// Mean throughput (records/s) of every worker in `[from, to)`, as `(worker, throughput)`.
+    /// Workers that processed nothing in the interval are reported with a throughput of zero.
+    fn worker_throughput_in(&self, from: u64, to: u64) -> Vec<(usize, f64)> {
+        let mut workers = self.workers.iter().map(|s| s.worker).collect::<Vec<_>>();
+        workers.dedup();
+        let span = to.saturating_sub(from);
+        workers.into_iter().map(|worker| {
+            let records: u64 = self.workers.iter().filter(|s| s.worker == worker && s.time >= from && s.time < to).map(|s| s.records).sum();
+            (worker, if span == 0 { 0. } else { records as f64 * 1_000_000_000. / span as f64 })
+        }).collect()
+    }
+
+    /// Mean of every latency percentile over the samples in `[from, to)`.
+    pub fn mean_percentiles(&self, from: u64, to: u64) -> Vec<Option<f64>> {
+        (0..PERCENTILES.len()).map(|p| mean(self.latency_in(p, from, to).map(|s| s.percentiles[p]))).collect()
+    }
+
+    /// Bootstrap phases of every spawned process, as `(process, [(phase, time, time since previous phase)])`.
+    pub fn bootstrap_breakdown(&self) -> Vec<(usize, PhaseBreakdown<'_>)> {
+        let mut processes = self.phases.iter().map(|p| p.process).collect::<Vec<_>>();
+        processes.sort();
+        processes.dedup();
+        processes.into_iter().map(|process| {
+            let mut previous = None;
+            let phases = self.phases.iter().filter(|p| p.process == process).map(|p| {
+                let delta = previous.map(|prev| p.time.saturating_sub(prev)).unwrap_or(0);
+                previous = Some(p.time);
+                (p.phase.as_str(), p.time, delta)
+            }).collect();
+            (process, phases)
+        }).collect()
+    }
+
+    /// Compute the KPIs of every rescale event. The analysis of an event stops at the
+    /// bootstrap of the following one.
+    pub fn kpis(&self, config: &KpiConfig) -> Vec<RescaleKpis> {
+        let p = config.percentile;
+        let end = self.latency.last().map(|s| s.time + 1).unwrap_or(0);
+
+        self.events.iter().enumerate().map(|(i, event)| {
+            let horizon = self.events.get(i + 1).map(|e| e.bootstrap).unwrap_or(end);
+
+            let before_from = event.bootstrap.saturating_sub(config.window_ns);
+            let before = mean(self.latency_in(p, before_from, event.bootstrap).map(|s| s.percentiles[p]));
+
+            let peak = self.latency_in(p, event.bootstrap, horizon)
+                .map(|s| (s.time, s.percentiles[p]))
+                .max_by_key(|&(_, latency)| latency);
+
+            let recovered_at = before.and_then(|baseline| {
+                let threshold = baseline * (1. + config.tolerance);
+                let candidates = self.latency_in(p, event.moved, horizon).collect::<Vec<_>>();
+                candidates
+                    .windows(config.stable_samples.max(1))
+                    .find(|w| w.iter().all(|s| s.percentiles[p] as f64 <= threshold))
+                    .map(|w| w[0].time)
+            });
+
+            let during_to = recovered_at.unwrap_or(horizon);
+            let during = mean(self.latency_in(p, event.bootstrap, during_to).map(|s| s.percentiles[p]));
+            let after = recovered_at.and_then(|t| mean(self.latency_in(p, t, (t + config.window_ns).min(horizon)).map(|s| s.percentiles[p])));
+
+            let steady_from = recovered_at.unwrap_or(event.moved);
+            let throughput_before = self.throughput_in(before_from, event.bootstrap);
+            let throughput_after = self.throughput_in(steady_from, (steady_from + config.window_ns).min(horizon));
+
+            let steady_to = (steady_from + config.window_ns).min(horizon);
+            let workers_before = self.worker_throughput_in(before_from, event.bootstrap);
+            let workers_after = self.worker_throughput_in(steady_from, steady_to);
+            let workers = workers_after.iter().map(|&(worker, after)| {
+                let before = workers_before.iter().find(|(w, _)| *w == worker).map(|(_, b)| *b);
+                (worker, before, Some(after))
+            }).collect();
+
+            let waves = self.waves.iter().filter(|w| w.time >= event.moved && w.time < horizon).cloned().collect();
+
+            let rollback = self.rollbacks.iter().find(|r| r.time >= event.moved && r.time < horizon).cloned();
+
+            let traffic = self.traffic.iter().find(|t| t.time >= event.moved && t.time < horizon).map(|t| t.traffic);
+
+            RescaleKpis { event: *event, before, during, after, peak, recovered_at, throughput_before, throughput_after, workers, waves, rollback, traffic }
+        }).collect()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn rescale_kpis() {
+        use crate::analysis::{KpiConfig, LatencySample, RescaleEvent, Run, ThroughputSample};
+
+        const MS: u64 = 1_000_000;
+        // p99 latency every 250ms: 10 at baseline, a spike while the new process joins at 4s and bins move at 4.5s
+        let latency = (0..40).map(|i| {
+            let time = i * 250 * MS;
+            let value = match time / MS {
+                4500 => 200,
+                4000..=4999 => 100,
+                _ => 10,
+            };
+            LatencySample { time, percentiles: vec![value; 7] }
+        }).collect();
+        // 1000 records/s before the rescale, 2000 records/s once latency recovered
+        let throughput = (0..10).map(|s| ThroughputSample { time: s * 1000 * MS, records: if s < 5 { 1000 } else { 2000 } }).collect();
+        let run = Run { latency, throughput, events: vec![RescaleEvent { bootstrap: 4000 * MS, moved: 4500 * MS }], ..Run::default() };
+
+        let kpis = run.kpis(&KpiConfig::default());
+        assert_eq!(kpis.len(), 1);
+        let k = &kpis[0];
+        assert_eq!(k.before, Some(10.));
+        assert_eq!(k.peak, Some((4500 * MS, 200)));
+        // the first 4 samples in a row within 10% of the baseline start at 5s
+        assert_eq!(k.recovered_at, Some(5000 * MS));
+        assert_eq!(k.recovery_ns(), Some(1000 * MS));
+        assert_eq!(k.during, Some(125.));
+        assert_eq!(k.after, Some(10.));
+        assert_eq!(k.throughput_before, Some(1000.));
+        assert_eq!(k.throughput_after, Some(2000.));
+        assert_eq!(k.throughput_change(), Some(1.));
+
+        // latency that never recovers leaves the during window open until the end of the run
+        let stuck = Run { latency: run.latency.iter().map(|s| LatencySample { time: s.time, percentiles: vec![if s.time < 4000 * MS { 10 } else { 100 }; 7] }).collect(), ..run };
+        let k = &stuck.kpis(&KpiConfig::default())[0];
+        assert_eq!((k.recovered_at, k.after), (None, None));
+        assert_eq!(k.during, Some(100.));
+    }
+
+    #[test]
+    fn rollback_records() {
+        use crate::analysis::Run;
+        use crate::metrics::MetricsFile;
+
+        let record = |kind: &str, fields: &[u64]| (kind.to_string(), fields.iter().map(|f| f.to_string()).collect());
+        let metrics = MetricsFile {
+            records: vec![
+                record("migration_rollback", &[10, 3]),
+                record("migration_rollback", &[20, 1]),
+                record("rollback_applied", &[10, 12]),
+                record("migration_uncommitted", &[30, 2, 4]),
+            ],
+            ..MetricsFile::default()
+        };
+        let run = Run::from_metrics(&metrics).unwrap();
+        assert_eq!(run.rollbacks.iter().map(|r| (r.time, r.applied)).collect::<Vec<_>>(), vec![(10, Some(12)), (20, None)]);
+        assert_eq!(run.uncommitted.iter().map(|u| (u.time, u.issued, u.remaining)).collect::<Vec<_>>(), vec![(30, 2, 4)]);
+
+        let orphan = MetricsFile { records: vec![record("rollback_applied", &[10, 12])], ..MetricsFile::default() };
+        assert!(Run::from_metrics(&orphan).is_err());
+    }
+}
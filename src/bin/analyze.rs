@@ -0,0 +1,227 @@
+//! Compute rescaling KPIs from the metrics written by the `benchmark` binary.
+//!
+//! rescaling-examples $ cargo run --bin analyze -- metrics --svg latency.svg
+//!
+//! For every rescale it reports the latency before, during and after the operation,
+//! the peak latency, the time until latency returned to its baseline and the throughput change.
+//! With `--svg` it also renders the latency timeline with the bootstrap and move markers.
+//! With `--baseline` it compares the latency with the one of another run of the same workload
+//! (e.g. a static or plain run, see `tools/compare.sh`) over the `--from`/`--to` interval.
+
+extern crate clap;
+
+use clap::{App, Arg};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+
+use rescaling_examples::analysis::{KpiConfig, Run, RescaleKpis, PERCENTILES};
+use rescaling_examples::metrics::MetricsFile;
+
+const NS_TO_SEC: f64 = 1_000_000_000.;
+const NS_TO_MILLIS: f64 = 1_000_000.;
+
+fn fmt_ms(latency: Option<f64>) -> String {
+    latency.map(|l| format!("{:.2}ms", l / NS_TO_MILLIS)).unwrap_or_else(|| "-".to_string())
+}
+
+fn print_kpis(kpis: &[RescaleKpis], config: &KpiConfig) {
+    println!("latency percentile: {}", PERCENTILES[config.percentile]);
+    for (i, k) in kpis.iter().enumerate() {
+        println!("rescale #{} (bootstrap at {:.2}s, moves at {:.2}s)", i, k.event.bootstrap as f64 / NS_TO_SEC, k.event.moved as f64 / NS_TO_SEC);
+        println!("\tlatency before:    {}", fmt_ms(k.before));
+        println!("\tlatency during:    {}", fmt_ms(k.during));
+        println!("\tlatency after:     {}", fmt_ms(k.after));
+        match k.peak {
+            Some((time, latency)) => println!("\tpeak latency:      {} at {:.2}s", fmt_ms(Some(latency as f64)), time as f64 / NS_TO_SEC),
+            None => println!("\tpeak latency:      -"),
+        }
+        match k.recovery_ns() {
+            Some(recovery) => println!("\ttime to baseline:  {:.2}s", recovery as f64 / NS_TO_SEC),
+            None => println!("\ttime to baseline:  never"),
+        }
+        match (k.throughput_before, k.throughput_after, k.throughput_change()) {
+            (Some(before), Some(after), Some(change)) =>
+                println!("\tthroughput:        {:.0} -> {:.0} records/s ({:+.1}%)", before, after, change * 100.),
+            _ => println!("\tthroughput:        -"),
+        }
+        if let (Some(first), Some(last)) = (k.waves.first(), k.waves.last()) {
+            println!("\tmigration:         {} waves, {} bins, ~{} bytes over {:.2}s",
+                     k.waves.len(), k.waves.iter().map(|w| w.bins).sum::<u64>(), k.waves.iter().map(|w| w.bytes).sum::<u64>(),
+                     (last.time - first.time) as f64 / NS_TO_SEC);
+        }
+        if let Some(traffic) = k.traffic {
+            println!("\ttraffic:           {} bins within processes, {} across processes, {} across hosts (~{} bytes across)",
+                     traffic.intra_process.bins, traffic.cross_process.bins, traffic.cross_host.bins,
+                     traffic.cross_process.bytes + traffic.cross_host.bytes);
+        }
+        if let Some(rollback) = k.rollback {
+            match rollback.applied {
+                Some(applied) => println!("\trolled back:       at {:.2}s, {} bins moved back, applied after {:.2}ms",
+                                          rollback.time as f64 / NS_TO_SEC, rollback.moves, (applied - rollback.time) as f64 / NS_TO_MILLIS),
+                None => println!("\trolled back:       at {:.2}s, {} bins moved back, not applied before the input ended",
+                                 rollback.time as f64 / NS_TO_SEC, rollback.moves),
+            }
+        }
+        for &(worker, before, after) in k.workers.iter() {
+            let fmt = |t: Option<f64>| t.map(|t| format!("{:.0}", t)).unwrap_or_else(|| "-".to_string());
+            println!("\t  worker {:>3}:      {} -> {} records/s", worker, fmt(before), fmt(after));
+        }
+    }
+}
+
+/// Render the latency timeline (p50, the analyzed percentile and max) as an SVG image.
+fn render_svg(run: &Run, config: &KpiConfig) -> String {
+    let (width, height, margin) = (1000., 450., 60.);
+    let max_time = run.latency.last().map(|s| s.time).unwrap_or(1).max(1) as f64;
+    let max_latency = run.latency.iter().flat_map(|s| s.percentiles.iter().cloned()).max().unwrap_or(1).max(1) as f64;
+    let x = |t: f64| margin + t / max_time * (width - 2. * margin);
+    let y = |l: f64| height - margin - l / max_latency * (height - 2. * margin);
+
+    let mut svg = String::new();
+    writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif" font-size="12">"#, width, height).unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+
+    // axes and ticks
+    writeln!(svg, r#"<line x1="{0}" y1="{1}" x2="{2}" y2="{1}" stroke="black"/>"#, margin, height - margin, width - margin).unwrap();
+    writeln!(svg, r#"<line x1="{0}" y1="{1}" x2="{0}" y2="{2}" stroke="black"/>"#, margin, margin, height - margin).unwrap();
+    for i in 0..=10 {
+        let t = max_time * i as f64 / 10.;
+        let l = max_latency * i as f64 / 10.;
+        writeln!(svg, r#"<text x="{}" y="{}" text-anchor="middle">{:.1}</text>"#, x(t), height - margin + 16., t / NS_TO_SEC).unwrap();
+        writeln!(svg, r#"<text x="{}" y="{}" text-anchor="end">{:.1}</text>"#, margin - 6., y(l) + 4., l / NS_TO_MILLIS).unwrap();
+    }
+    writeln!(svg, r#"<text x="{}" y="{}" text-anchor="middle">Time [s]</text>"#, width / 2., height - 15.).unwrap();
+    writeln!(svg, r#"<text x="15" y="{}" text-anchor="middle" transform="rotate(-90 15 {})">Latency [ms]</text>"#, height / 2., height / 2.).unwrap();
+
+    // rescale markers
+    for event in run.events.iter() {
+        for &(time, color) in [(event.bootstrap, "gold"), (event.moved, "blue")].iter() {
+            writeln!(svg, r#"<line x1="{0}" y1="{1}" x2="{0}" y2="{2}" stroke="{3}" stroke-width="3" stroke-opacity="0.3"/>"#,
+                     x(time as f64), margin, height - margin, color).unwrap();
+        }
+    }
+
+    // percentile lines
+    let mut series = vec![(1, "green"), (config.percentile, "red"), (PERCENTILES.len() - 1, "gray")];
+    series.dedup_by_key(|(p, _)| *p);
+    for (legend, &(p, color)) in series.iter().enumerate() {
+        let points = run.latency.iter()
+            .filter(|s| s.percentiles.len() > p)
+            .map(|s| format!("{:.1},{:.1}", x(s.time as f64), y(s.percentiles[p] as f64)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(svg, r#"<polyline fill="none" stroke="{}" stroke-width="1.5" points="{}"/>"#, color, points).unwrap();
+        let ly = margin + 16. * legend as f64;
+        writeln!(svg, r#"<text x="{}" y="{}" fill="{}">{}</text>"#, width - margin - 80., ly, color, PERCENTILES[p]).unwrap();
+    }
+    let ly = margin + 16. * series.len() as f64;
+    writeln!(svg, r#"<text x="{}" y="{}" fill="gold">bootstrap</text>"#, width - margin - 80., ly).unwrap();
+    writeln!(svg, r#"<text x="{}" y="{}" fill="blue">move</text>"#, width - margin - 80., ly + 16.).unwrap();
+
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+fn main() {
+    let matches = App::new("analyze")
+        .about("Compute rescaling KPIs from benchmark metrics")
+        .arg(Arg::with_name("METRICS").help("metrics file written by the benchmark").default_value("metrics"))
+        .arg(Arg::with_name("percentile").long("percentile").takes_value(true).default_value("p99")
+            .possible_values(&PERCENTILES).help("latency percentile to analyze"))
+        .arg(Arg::with_name("window").long("window").takes_value(true).default_value("2")
+            .help("length in seconds of the baseline and steady-state windows"))
+        .arg(Arg::with_name("tolerance").long("tolerance").takes_value(true).default_value("0.1")
+            .help("relative distance from the baseline at which latency is considered recovered"))
+        .arg(Arg::with_name("svg").long("svg").takes_value(true).help("render the latency timeline to this SVG file"))
+        .arg(Arg::with_name("baseline").long("baseline").takes_value(true).help("metrics file of a run to compare the latency with"))
+        .arg(Arg::with_name("from").long("from").takes_value(true).default_value("0")
+            .help("start in seconds of the interval compared with the baseline"))
+        .arg(Arg::with_name("to").long("to").takes_value(true).help("end in seconds of the interval compared with the baseline"))
+        .get_matches();
+
+    let path = matches.value_of("METRICS").unwrap();
+    let metrics = MetricsFile::read(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+    let run = Run::from_metrics(&metrics).unwrap_or_else(|e| panic!("invalid metrics in {}: {}", path, e));
+
+    let percentile = matches.value_of("percentile").unwrap();
+    let config = KpiConfig {
+        percentile: PERCENTILES.iter().position(|p| *p == percentile).unwrap(),
+        window_ns: (matches.value_of("window").unwrap().parse::<f64>().expect("invalid window") * NS_TO_SEC) as u64,
+        tolerance: matches.value_of("tolerance").unwrap().parse().expect("invalid tolerance"),
+        ..Default::default()
+    };
+
+    for (key, value) in metrics.metadata.iter() {
+        println!("{}: {}", key, value);
+    }
+    print_kpis(&run.kpis(&config), &config);
+
+    for (process, phases) in run.bootstrap_breakdown() {
+        match run.join_servers.iter().find(|j| j.process == process) {
+            Some(join) => println!("bootstrap of process {} (server: worker {})", process, join.server),
+            None => println!("bootstrap of process {}", process),
+        }
+        for (phase, time, delta) in phases {
+            println!("\t{:<18} at {:>8.2}s  (+{:.2}ms)", phase, time as f64 / NS_TO_SEC, delta as f64 / NS_TO_MILLIS);
+        }
+    }
+
+    for failure in run.spawn_failures.iter() {
+        println!("spawn attempt {} of process {} at {:.2}s failed: {}", failure.attempt, failure.process, failure.time as f64 / NS_TO_SEC, failure.reason);
+    }
+    for failure in run.failures.iter() {
+        println!("process {} failed: {}", failure.process, failure.status);
+    }
+
+    for plan in run.uncommitted.iter() {
+        println!("migration plan still open when the input ended at {:.2}s: {} bins issued, {} waves not issued",
+                 plan.time as f64 / NS_TO_SEC, plan.issued, plan.remaining);
+    }
+
+    if !run.statuses.is_empty() {
+        println!("bin status: {} requests, {} with differences", run.statuses.len(), run.statuses.iter().filter(|s| s.differences > 0).count());
+    }
+    for status in run.statuses.iter().filter(|s| s.differences > 0 || s.conflicts > 0) {
+        println!("bin status at {:.2}s: {} bins observed, {} moved while observed, {} differ from the assignment{}",
+                 status.time as f64 / NS_TO_SEC, status.observed, status.conflicts, status.differences,
+                 if status.reconciled { " (reconciled)" } else { "" });
+    }
+
+    let mut workers = run.state.iter().map(|s| s.worker).collect::<Vec<_>>();
+    workers.dedup();
+    for worker in workers {
+        let samples = run.state.iter().filter(|s| s.worker == worker).collect::<Vec<_>>();
+        let (first, last) = (samples[0], samples[samples.len() - 1]);
+        let max = samples.iter().map(|s| s.progress_entries).max().unwrap();
+        println!("progress state of worker {}: {} entries at {:.2}s, {} at {:.2}s (max {}, {} updates), {} pending configurations",
+                 worker, first.progress_entries, first.time as f64 / NS_TO_SEC, last.progress_entries, last.time as f64 / NS_TO_SEC,
+                 max, last.progress_updates, last.pending_controls);
+    }
+
+    if let Some(baseline_path) = matches.value_of("baseline") {
+        let baseline_metrics = MetricsFile::read(baseline_path).unwrap_or_else(|e| panic!("failed to read {}: {}", baseline_path, e));
+        let baseline = Run::from_metrics(&baseline_metrics).unwrap_or_else(|e| panic!("invalid metrics in {}: {}", baseline_path, e));
+        let from = (matches.value_of("from").unwrap().parse::<f64>().expect("invalid from") * NS_TO_SEC) as u64;
+        let to = matches.value_of("to").map(|t| (t.parse::<f64>().expect("invalid to") * NS_TO_SEC) as u64).unwrap_or(u64::MAX);
+        let mode = |m: &MetricsFile| m.metadata.get("mode").unwrap_or("unknown").to_string();
+        println!("latency of {} ({}) compared with {} ({}) in [{:.2}s, {})",
+                 path, mode(&metrics), baseline_path, mode(&baseline_metrics), from as f64 / NS_TO_SEC,
+                 matches.value_of("to").map(|t| format!("{}s]", t)).unwrap_or_else(|| "end]".to_string()));
+        let this = run.mean_percentiles(from, to);
+        let other = baseline.mean_percentiles(from, to);
+        for (p, (this, other)) in PERCENTILES.iter().zip(this.into_iter().zip(other)) {
+            match (this, other) {
+                (Some(this), Some(other)) if other > 0. =>
+                    println!("\t{:<6} {} vs {} ({:+.1}%)", p, fmt_ms(Some(this)), fmt_ms(Some(other)), (this / other - 1.) * 100.),
+                _ => println!("\t{:<6} {} vs {}", p, fmt_ms(this), fmt_ms(other)),
+            }
+        }
+    }
+
+    if let Some(svg_path) = matches.value_of("svg") {
+        let mut file = File::create(svg_path).expect("failed to create svg file");
+        file.write_all(render_svg(&run, &config).as_bytes()).expect("failed to write svg file");
+        println!("latency timeline written to {}", svg_path);
+    }
+}
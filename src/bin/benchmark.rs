@@ -14,7 +14,9 @@ static GLOBAL: System = System;
 
 use std::cell::RefCell;
 use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use streaming_harness::util::ToNanos;
 
@@ -25,19 +27,125 @@ use timely::dataflow::channels::pact::Pipeline;
 
 use dynamic_scaling_mechanism::{Control, ControlInst, BinId, BIN_SHIFT};
 use dynamic_scaling_mechanism::state_machine::BinnedStateMachine;
+use timely::dataflow::operators::aggregation::StateMachine;
 
 use timely::dataflow::operators::input::Handle;
-use rescaling_examples::{verify, LoadBalancer, LinesGenerator};
+use rescaling_examples::{parse_capacities, verify, LoadBalancer, LinesGenerator};
+use rescaling_examples::launcher::{self, Cluster, Hostfile};
+use rescaling_examples::metrics::{Accumulation, Metadata, MetricsWriter, WorkerTimelines};
+use rescaling_examples::migration::{self, MigrationSchedule, Pacing, PlanPreview, Strategy, WaveLimits};
+use rescaling_examples::placement::{JoinPolicy, Placement, Topology, Traffic};
+use rescaling_examples::status::bin_status;
+use rescaling_examples::supervisor::{self, Failure, Supervisor};
 use timely::dataflow::operators::inspect::Inspect;
-use std::process::Command;
-use std::fs::File;
 use colored::Colorize;
-use std::collections::VecDeque;
-use std::io::Write;
+use std::collections::{HashMap, VecDeque};
 use timely::dataflow::operators::map::Map;
 use timely::dataflow::operators::exchange::Exchange;
+use timely::logging::TimelyEvent;
 
 const WORKER_BOOTSTRAP_MARGIN: u64 = 500_000_000; // wait 500 millis after spawning before sending move commands
+const TIMELINE_BUCKET_NS: u64 = 250_000_000;
+const JOIN_LOAD_WINDOW_NS: u64 = 2_000_000_000; // recent load considered when choosing a bootstrap server
+const SUPERVISOR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10); // time spawned processes get to exit after the run
+
+/// How the word count is run, to separate the overhead of the rescaling machinery
+/// from the effect of running with a different number of workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Megaphone operators, processes are added according to the rescale schedule
+    Rescaled,
+    /// Megaphone operators, the cluster keeps the size it is started with
+    Static,
+    /// Plain timely `state_machine`, the cluster keeps the size it is started with
+    Plain,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rescaled" => Ok(Mode::Rescaled),
+            "static" => Ok(Mode::Static),
+            "plain" => Ok(Mode::Plain),
+            _ => Err(format!("unknown mode {:?}, expected one of rescaled, static, plain", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mode::Rescaled => write!(f, "rescaled"),
+            Mode::Static => write!(f, "static"),
+            Mode::Plain => write!(f, "plain"),
+        }
+    }
+}
+
+/// How the data generator reacts to the dataflow falling behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Load {
+    /// Produce only while the output frontier keeps up with the input, stamping late records
+    /// with the time they are actually produced at
+    Closed,
+    /// Produce every record on schedule regardless of backpressure, stamped with the time it
+    /// should have been sent at, so latency includes the time spent waiting to be produced
+    Open,
+}
+
+impl std::str::FromStr for Load {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "closed" => Ok(Load::Closed),
+            "open" => Ok(Load::Open),
+            _ => Err(format!("unknown load {:?}, expected one of closed, open", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Load {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Load::Closed => write!(f, "closed"),
+            Load::Open => write!(f, "open"),
+        }
+    }
+}
+
+/// The times at which records are scheduled: `1 + k * interval` for every `k` such that the time
+/// is before `end`, the same as `ConstantThroughputInputTimes::new(1, interval, end)`.
+#[derive(Debug, Clone, Copy)]
+struct Schedule {
+    interval: u64,
+    end: u64,
+}
+
+impl Schedule {
+    /// Records scheduled in `[from, until)`, as `(k, time)`.
+    fn between(&self, from: u64, until: u64) -> impl Iterator<Item=(u64, u64)> {
+        let interval = self.interval;
+        let until = ::std::cmp::min(until, self.end);
+        let first = (from.saturating_sub(1) + interval - 1) / interval;
+        let last = (until.saturating_sub(1) + interval - 1) / interval;
+        (first..last).map(move |k| (k, 1 + k * interval))
+    }
+}
+
+/// Read the environment variable `name`, falling back to `default` if it is not set.
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T where T::Err: std::fmt::Debug {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|e| panic!("invalid {} env var: {:?}", name, e)),
+        Err(_) => default,
+    }
+}
+
+/// Nanoseconds since the UNIX epoch, used to relate events of different processes on the same host.
+fn unix_ns() -> u64 {
+    ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap().to_nanos()
+}
 
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut h: ::fnv::FnvHasher = Default::default();
@@ -45,77 +153,304 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
     h.finish()
 }
 
+/// Measurements taken by worker 0 during the run.
+struct Measurements {
+    summary_timeline: String,
+    spawn_metrics: Vec<(u64, u64)>,
+    /// Scheduled records (offered load) of every timeline bucket, by intended send time.
+    produced: Vec<u64>,
+    /// Records processed in every timeline bucket, by processing time.
+    processed: Vec<u64>,
+    worker_timelines: WorkerTimelines,
+    /// Bootstrap protocol phases as `(process, phase, time)`, see `BOOTSTRAP_PHASES`.
+    bootstrap_phases: Vec<(usize, &'static str, u64)>,
+    /// State size samples as `(worker, time, progress entries, progress updates, pending controls)`.
+    state_samples: Vec<(usize, u64, usize, u64, usize)>,
+    /// The spawned processes, reaped once the computation terminated.
+    supervisor: Supervisor,
+    /// Failed spawn attempts as `(process, attempt, time, reason)`.
+    spawn_failures: Vec<(usize, usize, u64, String)>,
+    /// Bootstrap server of every joined process, as `(process, worker)`.
+    join_servers: Vec<(usize, usize)>,
+    /// Issued migration waves as `(time, bins, estimated bytes)`.
+    migration_waves: Vec<(u64, usize, u64)>,
+    /// Rolled back migration plans as `(time, compensating moves)`.
+    rollbacks: Vec<(u64, usize)>,
+    /// Applied compensating configurations, as `(time of the rollback, time applied)`.
+    rollbacks_applied: Vec<(u64, u64)>,
+    /// Plans still open when the input ended, as `(time, issued bins, waves not issued)`.
+    uncommitted: Vec<(u64, usize, usize)>,
+    /// Status requests as `(time, observed bins, conflicting bins, differences, reconciled)`.
+    bin_statuses: Vec<(u64, usize, usize, usize, bool)>,
+    /// Traffic of every migration plan, as `(time, traffic)`.
+    plan_traffic: Vec<(u64, Traffic)>,
+    /// Records of every bin observed by status requests, as `(time, records)`.
+    bin_loads: Vec<(u64, Vec<u64>)>,
+}
+
+/// The traffic of the plan from the assignment of `snapshot` to the one of `load_balancer`, measured
+/// on the actual `topology` whether or not the load balancer knew about it.
+fn plan_traffic_of(snapshot: &LoadBalancer, load_balancer: &LoadBalancer, topology: &Topology, bin_bytes: &[u64]) -> Traffic {
+    PlanPreview { current: snapshot.assignment(), proposed: load_balancer.assignment(), bin_bytes: bin_bytes.to_vec() }.traffic(topology)
+}
+
+/// Abort the run if a spawned process failed or Ctrl-C was pressed, killing all spawned processes.
+/// The failures of `joining`, the processes currently joining the cluster, are returned instead,
+/// so that their spawn can be retried.
+fn check_supervised(supervisor: &mut Supervisor, joining: &[usize]) -> Vec<Failure> {
+    exit_if_interrupted(supervisor);
+    let (joining_failure, failures): (Vec<_>, Vec<_>) = supervisor.poll().into_iter().partition(|f| joining.contains(&f.process));
+    if !failures.is_empty() {
+        for failure in failures.iter() {
+            eprint!("{}", failure);
+        }
+        supervisor.kill_all();
+        std::process::exit(1);
+    }
+    joining_failure
+}
+
+/// Exit if Ctrl-C was pressed, killing all spawned processes.
+fn exit_if_interrupted(supervisor: &mut Supervisor) {
+    if supervisor::interrupted() {
+        eprintln!("interrupted, killing {} spawned processes", supervisor.running());
+        supervisor.kill_all();
+        std::process::exit(130);
+    }
+}
+
+/// Phases of adding a process to the cluster, in the order they are expected to happen:
+/// * `spawn`: worker 0 starts the new process
+/// * `process_start`: the new process enters `main`
+/// * `connected`: the new process established connections to the cluster and started its workers
+/// * `peers_changed`: worker 0 sees the increased number of peers
+/// * `bootstrapped`: `worker.bootstrap()` returned on the new process
+/// * `bootstrap_sent`: worker 0 sent the `Bootstrap` controls for the new workers
+/// * `bootstrap_applied`: the frontier of the new workers passed the first control they received
+/// * `first_record`: worker 0 collected the first processed-records report of the new workers
+/// * `moves_sent`: worker 0 sent the `Move` controls migrating bins to the new workers
+const BOOTSTRAP_PHASES: [&str; 9] = ["spawn", "process_start", "connected", "peers_changed", "bootstrapped",
+    "bootstrap_sent", "bootstrap_applied", "first_record", "moves_sent"];
+
+fn phase(name: &str) -> usize {
+    BOOTSTRAP_PHASES.iter().position(|p| *p == name).expect("unknown bootstrap phase")
+}
+
 fn main() {
-    let rate: u64 = 100;
-    let duration_ns: u64 = 40*1_000_000_000;
+    let process_start = unix_ns();
+    let joining = std::env::args().any(|arg| arg == "--join");
+
+    let mode: Mode = env_or("MODE", Mode::Rescaled);
+    let load: Load = env_or("LOAD", Load::Closed);
+    let rate: u64 = env_or("RATE", 100);
+    let duration_ns: u64 = env_or("DURATION_S", 40) * 1_000_000_000;
     let validate = false;
-    let key_space = 1000;
+    let key_space: usize = env_or("KEY_SPACE", 1000);
     let words_per_line = 100;
     let word_length = 10;
+    let metrics_path = std::env::var("METRICS").unwrap_or_else(|_| "metrics".to_string());
+    // How new processes are started and where their output goes, see `rescaling_examples::launcher`
+    let launcher_spec = std::env::var("LAUNCHER").unwrap_or_else(|_| "exe".to_string());
+    let run_dir = std::env::var("RUN_DIR").unwrap_or_else(|_| "/tmp".to_string());
+    let launcher_spec2 = launcher_spec.clone();
+    let run_dir2 = run_dir.clone();
+    // Hostfile the initial processes were started with (`-h`), extended with a free local port for every spawned process
+    let hostfile_path = std::env::var("HOSTFILE").ok();
+    let hostfile_path2 = hostfile_path.clone();
+    // Sampling the state size requires logging every progress message, so it is opt-in
+    let sample_state = std::env::var("SAMPLE_STATE").map(|v| v == "1").unwrap_or(false);
+
+    // A spawned process that did not join the cluster within the timeout is killed and spawned again,
+    // up to `SPAWN_RETRIES` times, after which the rescale is skipped
+    let join_timeout = Duration::from_secs(env_or("JOIN_TIMEOUT_S", 30));
+    let spawn_retries: usize = env_or("SPAWN_RETRIES", 1);
+    // How the bootstrap server of a spawned process is chosen, see `rescaling_examples::placement`
+    let join_policy: JoinPolicy = env_or("JOIN_POLICY", JoinPolicy::RoundRobin);
+    // Processes added by every scale-out, launched in parallel and migrated to with a single plan
+    let spawn_batch: usize = env_or("SPAWN_BATCH", 1);
+    assert!(spawn_batch > 0, "SPAWN_BATCH must be positive");
+    // Migration plans are cut into configurations by the `MIGRATION` strategy (all-at-once, fluid or batched:K)
+    // and into waves of at most `WAVE_BINS` bins and `WAVE_BYTES` (estimated) bytes of state. The next wave is
+    // issued right away (`PACING=immediate`), once the previous one was applied (`PACING=ack`) or after a fixed
+    // interval (e.g. `PACING=500ms`)
+    let strategy: Strategy = env_or("MIGRATION", Strategy::AllAtOnce);
+    let wave_limits = strategy.limits(WaveLimits {
+        max_bins: std::env::var("WAVE_BINS").ok().map(|v| v.parse().expect("invalid WAVE_BINS")),
+        max_bytes: std::env::var("WAVE_BYTES").ok().map(|v| v.parse().expect("invalid WAVE_BYTES")),
+    });
+    let pacing: Pacing = env_or("PACING", strategy.default_pacing());
+    // A plan whose last wave was not applied within `MIGRATION_DEADLINE_S` seconds is rolled back
+    let migration_deadline_ns: u64 = env_or("MIGRATION_DEADLINE_S", 30) * 1_000_000_000;
+    // Every `STATUS_INTERVAL_S` seconds worker 0 asks the workers which bins they processed records of,
+    // 0 (the default) disables status requests. Plain mode has no bins and never sends them. With `RECONCILE=true` it adopts the observed workers where they differ
+    // from its assignment
+    let status_interval_ns: u64 = env_or("STATUS_INTERVAL_S", 0) * 1_000_000_000;
+    let reconcile: bool = env_or("RECONCILE", false);
+    let status_enabled = mode != Mode::Plain && status_interval_ns > 0;
+    // Capacity weights of the workers (`CAPACITIES=WORKER:WEIGHT,...`, default 1), also of the workers spawned
+    // later. Bins are balanced in proportion to them, a first plan rebalances the initial workers
+    let capacities_spec = std::env::var("CAPACITIES").unwrap_or_default();
+    let capacities = parse_capacities(&capacities_spec).unwrap_or_else(|e| panic!("invalid CAPACITIES: {}", e));
+    // Whether the load balancer knows which process and host every worker runs on
+    let placement: Placement = env_or("BIN_PLACEMENT", Placement::Topology);
+    // Estimated state of all bins, split among them by the records status requests observed (see `migration::bin_sizes`)
+    let state_bytes = migration::bin_bytes(key_space as u64, 1 << BIN_SHIFT) << BIN_SHIFT;
+
+    let spawn_schedule = if mode == Mode::Rescaled { vec![duration_ns/3, 2*duration_ns/3] } else { vec![] };
+    let spawn_schedule2 = spawn_schedule.clone();
 
     let timelines: Vec<_> = timely::execute_from_args(std::env::args(), move |worker| {
+        // connections to the other processes are established before the workers start
+        let connected = unix_ns();
 
         let peers = worker.peers();
         let index = worker.index();
 
         // Declare re-used input, control and probe handles.
-        let mut input: Handle<_, ()> = InputHandle::new();
+        let mut input: Handle<_, (u64, u64, usize)> = InputHandle::new();
         let mut control_input = InputHandle::new();
+        let mut status_input = InputHandle::new();
         // let mut control_input_2 = InputHandle::new();
         let mut probe = ProbeHandle::new();
-        let probe2 = ProbeHandle::clone(&mut probe);
 
         // Generate the times at which input should be produced
         let input_times = || streaming_harness::input::ConstantThroughputInputTimes::<u64, u64>::new(
             1, 1_000_000_000 / rate, duration_ns);
-
-        let mut input_times_gen =
-            ::streaming_harness::input::SyntheticInputTimeGenerator::new(input_times());
+        let schedule = Schedule { interval: 1_000_000_000 / rate, end: duration_ns };
 
         let element_hdr = Rc::new(RefCell::new(::hdrhist::HDRHist::new()));
         let element_hdr2 = Rc::clone(&element_hdr);
 
+        // Processed records and latency of every worker, collected at worker 0
+        let worker_timelines = Rc::new(RefCell::new(WorkerTimelines::new(TIMELINE_BUCKET_NS)));
+        let worker_timelines2 = Rc::clone(&worker_timelines);
+        // Records processed by all the workers, bucketed by the time worker 0 learned about them
+        let processed = Rc::new(RefCell::new(Vec::<u64>::new()));
+        let processed2 = Rc::clone(&processed);
+
+        // Input timestamps are nanoseconds since this instant (only meaningful on worker 0)
+        let timer = ::std::time::Instant::now();
+        let epoch = unix_ns();
+
+        // Arrival time (ns since `timer`) of the first processed-records report of every worker
+        let first_report = Rc::new(RefCell::new(HashMap::new()));
+        let first_report2 = Rc::clone(&first_report);
+
+        // Set once `worker.bootstrap()` returns on a joining worker
+        let bootstrapped = Rc::new(RefCell::new(None::<u64>));
+        let bootstrapped2 = Rc::clone(&bootstrapped);
+
+        // Phases reported by the joining workers, as `(worker, phase, unix time)`
+        let joined_phases = Rc::new(RefCell::new(Vec::new()));
+        let joined_phases2 = Rc::clone(&joined_phases);
+
+        // Accumulated progress updates received by this worker: the progress state a bootstrap
+        // server hands to a joining worker. Rebuilt from the progress log, as the tracker is internal
+        // to timely. The logger must be registered before the dataflow is constructed.
+        let progress_state = Rc::new(RefCell::new(Accumulation::default()));
+        if sample_state {
+            let progress_state = Rc::clone(&progress_state);
+            worker.log_register().insert::<TimelyEvent, _>("timely", move |_time, data| {
+                let mut progress_state = progress_state.borrow_mut();
+                for (_, _, event) in data.iter() {
+                    if let TimelyEvent::Progress(progress) = event {
+                        if !progress.is_send {
+                            for (node, port, time, delta) in progress.messages.iter().chain(progress.internal.iter()) {
+                                progress_state.update((progress.addr.clone(), *node, *port, time.clone()), *delta);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        // Timestamps of the control messages received by this worker whose configuration is still pending
+        let pending_controls = Rc::new(RefCell::new(Vec::new()));
+        let pending_controls2 = Rc::clone(&pending_controls);
+
+        // Bin status merged at worker 0, see `rescaling_examples::status`
+        let statuses = Rc::new(RefCell::new(Vec::new()));
+        let statuses2 = Rc::clone(&statuses);
+
+        // State size samples of every worker, collected at worker 0
+        let state_samples = Rc::new(RefCell::new(Vec::new()));
+        let state_samples2 = Rc::clone(&state_samples);
+
         // Construct the dataflow
         worker.dataflow(|scope: &mut ::timely::dataflow::scopes::Child<_, usize>| {
             let control = control_input.to_stream(scope).broadcast();
             control.inspect(move |c| println!("[W{}] {}", index, format!("control message is {:?}", c).bold().yellow()));
+            if sample_state {
+                control.inspect_batch(move |time, controls| pending_controls2.borrow_mut().extend(controls.iter().map(|_| *time)));
+            }
+
+            // A joining worker reports the phases it went through once the first control it received
+            // (its own `Bootstrap`) has been applied. It holds no capability of its own, so the report
+            // is sent using the one of the control message.
+            let mut requested = false;
+            let mut reported = false;
+            control
+                .unary_notify(Pipeline, "BootstrapPhases", vec![], move |input, output, notificator| {
+                    input.for_each(|time, _data| {
+                        if joining && !requested {
+                            notificator.notify_at(time.retain());
+                            requested = true;
+                        }
+                    });
+                    notificator.for_each(|time, _, _| {
+                        if !reported {
+                            let mut phases = vec![
+                                (index, phase("process_start"), process_start),
+                                (index, phase("connected"), connected),
+                                (index, phase("bootstrap_applied"), unix_ns()),
+                            ];
+                            if let Some(bootstrapped) = *bootstrapped2.borrow() {
+                                phases.push((index, phase("bootstrapped"), bootstrapped));
+                            }
+                            output.session(&time).give_iterator(phases.into_iter());
+                            reported = true;
+                        }
+                    });
+                })
+                .exchange(|_| 0)
+                .inspect(move |report| joined_phases2.borrow_mut().push(*report));
 
-            // Construct the data generator
+            // Construct the data generator. Worker 0 broadcasts ticks `(from, until, peers)` and every
+            // worker that was in the cluster when a tick was sent produces its share of the records
+            // scheduled in `[from, until)`. Joining workers hold no capabilities of their own, so they
+            // produce their share using the capabilities of the ticks.
             let lines = input
                 .to_stream(scope)
-                .unary_frontier(Pipeline, "Data generator", |mut cap, _info| {
-                    let mut lines_generator = LinesGenerator::new(key_space, words_per_line, word_length);
-                    let mut last_production_time = 0;
+                .broadcast()
+                .unary(Pipeline, "Data generator", move |_cap, _info| {
+                    let mut lines_generator = LinesGenerator::new_seeded(key_space, words_per_line, word_length, index as u64);
 
                     move |input, output| {
-                        // Input closed, we're done
-                        if input.frontier().is_empty() {
-                            cap.take();
-                        } else if let Some(cap) = cap.as_mut() {
-                            let current_time = input.frontier().frontier()[0];
-                            let probe_time = probe2.with_frontier(|f| if f.is_empty() { 0 } else { f[0] });
-                            let delta_probe = current_time - probe_time;
-                            let delta_production = current_time - last_production_time;
-                            // if delta to probe is smaller than half of delta to production, consider to produce more data
-                            if delta_probe <= delta_production * 2 {
-                                if let Some(mut it) = input_times_gen.iter_until((current_time) as u64) {
-                                    // `it` is some => we are still running!
-                                    // If there are actual elements to be produced, open a session and produce them
-                                    if let Some(_) = it.next() {
-                                        let mut session = output.session(cap);
-                                        session.give(lines_generator.next());
-                                        let mut word_count = words_per_line;
-                                        for _t in it {
-                                            session.give(lines_generator.next());
-                                            word_count += words_per_line;
-                                        }
-                                        element_hdr2.borrow_mut().add_value(word_count as u64);
-                                        last_production_time = current_time;
+                        input.for_each(|time, ticks| {
+                            let mut word_count = 0;
+                            // stamps never decrease within a tick: the records of a stamp share one session
+                            let mut pending: Option<(usize, Vec<String>)> = None;
+                            for &(from, until, peers) in ticks.iter() {
+                                for (_, t) in schedule.between(from, until).filter(|(k, _)| *k as usize % peers == index) {
+                                    let stamp = match load {
+                                        Load::Closed => *time.time(),
+                                        Load::Open => ::std::cmp::max(t as usize, *time.time()),
+                                    };
+                                    if pending.as_ref().map(|(s, _)| *s != stamp).unwrap_or(false) {
+                                        let (stamp, lines) = pending.take().unwrap();
+                                        output.session(&time.delayed(&stamp)).give_iterator(lines.into_iter());
                                     }
+                                    pending.get_or_insert_with(|| (stamp, Vec::new())).1.push(lines_generator.next());
+                                    word_count += words_per_line;
                                 }
-                                cap.downgrade(&current_time);
                             }
-                        }
+                            if let Some((stamp, lines)) = pending {
+                                output.session(&time.delayed(&stamp)).give_iterator(lines.into_iter());
+                            }
+                            if word_count > 0 {
+                                element_hdr2.borrow_mut().add_value(word_count as u64);
+                            }
+                        });
                     }
                 });
 
@@ -130,17 +465,85 @@ fn main() {
                             .collect::<Vec<_>>()
                     );
 
-            let sst_output =
+            let sst_output = if mode == Mode::Plain {
+                // plain exchange computes correct counts as long as the cluster is not rescaled
+                words
+                    .state_machine(|key: &String, val, agg: &mut u64| {
+                        *agg += val;
+                        (false, Some((key.clone(), *agg)))
+                    }, |key| calculate_hash(key))
+            } else {
                 words
                     .stateful_state_machine(|key: &String, val, agg: &mut u64| {
                         *agg += val;
                         (false, Some((key.clone(), *agg)))
                     }, |key| calculate_hash(key), &control)
-                    //.inspect(move |x| println!("{:?}", x))
-                    .probe_with(&mut probe);
+            };
+            sst_output.probe_with(&mut probe);
+
+            // Every worker answers the status requests of worker 0 with the bins it processed records of
+            let status_requests = status_input.to_stream(scope).broadcast();
+            if status_enabled {
+                bin_status(&sst_output, &status_requests, |(word, _): &(String, u64)| calculate_hash(word))
+                    .inspect(move |status| statuses2.borrow_mut().push(status.clone()));
+            }
+
+            // Every worker, including the ones joining later, reports how many records it processed
+            // at each timestamp. Worker 0 collects the reports and measures their latency against the
+            // clock that assigned the input timestamps, so no clock synchronization is needed.
+            sst_output
+                .unary(Pipeline, "WorkerStats", move |_cap, _info| {
+                    move |input, output| {
+                        input.for_each(|time, data| {
+                            output.session(&time).give((index, data.len()));
+                        });
+                    }
+                })
+                .exchange(|_| 0)
+                .inspect_batch(move |time, reports| {
+                    let elapsed_ns = timer.elapsed().to_nanos();
+                    let latency = elapsed_ns.saturating_sub(*time as u64);
+                    let mut worker_timelines = worker_timelines2.borrow_mut();
+                    let mut first_report = first_report2.borrow_mut();
+                    let mut processed = processed2.borrow_mut();
+                    let bucket = (elapsed_ns / TIMELINE_BUCKET_NS) as usize;
+                    if processed.len() <= bucket { processed.resize(bucket + 1, 0); }
+                    for &(worker, records) in reports.iter() {
+                        worker_timelines.add(worker, *time as u64, latency, records as u64);
+                        processed[bucket] += records as u64;
+                        first_report.entry(worker).or_insert(elapsed_ns);
+                    }
+                });
+
+            // Every worker samples the size of its progress state and of the pending configurations
+            // at most once per timeline bucket. A configuration is pending until the output frontier
+            // passes its timestamp, as Megaphone applies it only then.
+            if sample_state {
+                let probe3 = probe.clone();
+                sst_output
+                    .unary(Pipeline, "StateSize", move |_cap, _info| {
+                        let mut last_sample: Option<::std::time::Instant> = None;
+                        move |input, output| {
+                            input.for_each(|time, _data| {
+                                if last_sample.map(|s| s.elapsed().to_nanos() >= TIMELINE_BUCKET_NS).unwrap_or(true) {
+                                    last_sample = Some(::std::time::Instant::now());
+                                    let mut pending = pending_controls.borrow_mut();
+                                    pending.retain(|t| probe3.less_equal(t));
+                                    let progress = progress_state.borrow();
+                                    output.session(&time).give((index, progress.len(), progress.updates(), pending.len()));
+                                }
+                            });
+                        }
+                    })
+                    .exchange(|_| 0)
+                    .inspect_batch(move |time, samples| {
+                        state_samples2.borrow_mut().extend(samples.iter().map(|&(worker, entries, updates, pending)| {
+                            (worker, *time as u64, entries, updates, pending)
+                        }));
+                    });
+            }
 
             if validate {
-                use timely::dataflow::operators::aggregation::StateMachine;
                 let correct = words
                     .state_machine(|key: &String, val, agg: &mut u64| {
                         *agg += val;
@@ -150,120 +553,323 @@ fn main() {
             }
         });
 
-        if worker.bootstrap() { return None; }
+        // Joining workers have no input to inject: their processed records are reported by the
+        // "WorkerStats" operator while the worker runs to completion.
+        if worker.bootstrap() {
+            *bootstrapped.borrow_mut() = Some(unix_ns());
+            return None;
+        }
 
-        let mut spawn_at_times: VecDeque<u64> = VecDeque::new();
-        spawn_at_times.push_back(duration_ns/3);
-        spawn_at_times.push_back(2*duration_ns/3);
+        let mut spawn_at_times: VecDeque<u64> = spawn_schedule2.iter().cloned().collect();
 
         let mut spawn_metrics = Vec::new();
+        let mut spawn_failures = Vec::new();
+        let mut bootstrap_phases = Vec::new();
 
         let mut output_metric_collector =
             ::streaming_harness::output::default::hdrhist_timeline_collector(
                 input_times(),
                 0, 2_000_000_000, duration_ns - 2_000_000_000, duration_ns,
-                250_000_000);
+                TIMELINE_BUCKET_NS);
 
         let n = std::env::var("N").expect("missing N env var -- number of processes").parse::<usize>().unwrap();
         let w = std::env::var("W").expect("missing W env var -- number of workers").parse::<usize>().unwrap();
-        let mut p = n;
-        let mut nn = n+1;
-        let mut join = 0;
+        let hostfile = hostfile_path2.as_ref().map(|path| Hostfile::read(path).unwrap_or_else(|e| panic!("failed to read HOSTFILE {}: {}", path, e)));
+        if let Some(hostfile) = hostfile.as_ref() {
+            assert!(hostfile.addresses().len() >= n, "HOSTFILE has fewer than N addresses");
+        }
+        // the workers only grow, so a fixed bootstrap server valid now stays valid
+        if let Err(e) = join_policy.select(n * w, 0, &HashMap::new(), &[]) {
+            panic!("invalid JOIN_POLICY: {}", e);
+        }
+        let mut cluster = Cluster::new(n, w, hostfile);
+        let mut join_servers = Vec::new();
+        let mut migration_waves = Vec::new();
+        // The plan being executed, the bin assignment to roll back to and the deadline to commit it
+        let mut migration: Option<(MigrationSchedule, LoadBalancer, u64)> = None;
+        let mut rollbacks = Vec::new();
+        // Time of the last rollback and control time of its compensating configuration, until it was applied
+        let mut compensation: Option<(u64, usize)> = None;
+        let mut rollbacks_applied = Vec::new();
+        let mut uncommitted = Vec::new();
         let mut spawn_info = None;
+        // Control time of the last configuration moving bins, and the pending status requests with
+        // the time they were issued and the time of the previous request, where the interval they cover starts
+        let mut last_config_time = None;
+        let mut status_request = 0;
+        let mut status_windows = HashMap::new();
+        let mut last_status = (0, 0);
+        let mut bin_statuses = Vec::new();
+        let mut plan_traffic = Vec::new();
+        let mut bin_loads = Vec::new();
+        // Records of every bin over all status requests, the estimated state is split among bins in proportion to them
+        let mut bin_records = vec![0; 1 << BIN_SHIFT];
+        // Estimated state size of every bin when the current plan was made
+        let mut plan_sizes = Vec::new();
+
+        let launcher = launcher::from_spec(&launcher_spec2).unwrap_or_else(|e| panic!("invalid LAUNCHER: {}", e));
+        // Spawned processes are killed when worker 0 fails, is interrupted or panics (on drop).
+        // Only worker 0 handles Ctrl-C, the other processes keep the default behavior.
+        let mut supervisor = Supervisor::new();
+        let mut last_poll = Instant::now();
+        if index == 0 {
+            supervisor::install_interrupt_handler().unwrap_or_else(|e| panic!("failed to install Ctrl-C handler: {}", e));
+        }
 
         let mut load_balancer = LoadBalancer::new((0..peers).collect(), 1 << BIN_SHIFT);
+        for &(worker, capacity) in capacities.iter() {
+            load_balancer.set_capacity(worker, capacity);
+        }
+        match placement {
+            Placement::Topology => load_balancer.set_topology(cluster.topology()),
+            Placement::Records => load_balancer.set_bin_loads(bin_records.clone()),
+            Placement::Flat => {},
+        }
+        let snapshot = load_balancer.clone();
+        let moves = load_balancer.rebalance().collect::<Vec<_>>();
+        if !moves.is_empty() {
+            plan_sizes = migration::bin_sizes(state_bytes, &bin_records);
+            plan_traffic.push((0, plan_traffic_of(&snapshot, &load_balancer, &cluster.topology(), &plan_sizes)));
+            let waves = migration::split_waves(moves, wave_limits, |bin| plan_sizes[bin]);
+            migration = Some((MigrationSchedule::new(waves, pacing), snapshot, migration_deadline_ns));
+        }
 
         let mut input = Some(input);
         let mut control_input = Some(control_input);
+        let mut status_input = Some(status_input);
         let mut control_sequence = 0;
 
-        let timer = ::std::time::Instant::now();
+        // Start of the interval the next tick covers, and number of records (words) scheduled in every timeline bucket
+        let mut last_tick = 0;
+        let mut produced = Vec::new();
 
         loop {
             if index != 0 {
                 input.take().unwrap().close();
                 control_input.take().unwrap().close();
+                status_input.take().unwrap().close();
                 break;
             }
 
             let elapsed_ns = timer.elapsed().to_nanos();
 
+            if last_poll.elapsed() >= SUPERVISOR_POLL_INTERVAL {
+                check_supervised(&mut supervisor, &[]);
+                last_poll = Instant::now();
+            }
+
             if let Some(spawn_at_time) = spawn_at_times.front() {
-                if elapsed_ns >= *spawn_at_time && spawn_info.is_none() {
+                if elapsed_ns >= *spawn_at_time && spawn_info.is_none() && migration.is_none() && compensation.is_none() {
                     spawn_at_times.pop_front();
 
+                    // choose the bootstrap servers of the `spawn_batch` processes joining in this scale-out
                     let old_peers = worker.peers();
-
-                    let stdout = File::create(format!("/tmp/process-{}-stdout", p)).unwrap();
-                    let stderr = File::create(format!("/tmp/process-{}-stderr", p)).unwrap();
-
-                    Command::new("cargo")
-                        .stdout(stdout)
-                        .stderr(stderr)
-                        .arg("run")
-                        .arg("--bin")
-                        .arg("benchmark")
-                        .arg("--")
-                        .arg("-n")
-                        .arg(n.to_string())
-                        .arg("-w")
-                        .arg(w.to_string())
-                        .arg("-p")
-                        .arg(p.to_string())
-                        .arg("--join")
-                        .arg(join.to_string())
-                        .arg("--nn")
-                        .arg(nn.to_string())
-                        .spawn()
-                        .expect("failed to spawn new process");
-
-                    // wait for the new worker to join the cluster
-                    while old_peers == worker.peers() {
-                        worker.step();
+                    let recent_load = worker_timelines.borrow().since(elapsed_ns.saturating_sub(JOIN_LOAD_WINDOW_NS));
+                    let mut joins = Vec::with_capacity(spawn_batch);
+                    for _ in 0..spawn_batch {
+                        let join = join_policy.select(old_peers, join_servers.len() + joins.len(), &recent_load, &joins)
+                            .unwrap_or_else(|e| panic!("invalid JOIN_POLICY: {}", e));
+                        joins.push(join);
                     }
 
-                    (0..w)
-                        .map(|i| ControlInst::Bootstrap(join, p*w+i))
-                        .map(|cmd| Control::new(control_sequence, w, cmd))
-                        .for_each(|ctrl| control_input.as_mut().unwrap().send(ctrl));
+                    // Time of the spawn attempt whose processes joined the cluster. The batch is retried as
+                    // a whole as long as none of its workers arrived, a partial join cannot be undone.
+                    let mut joined = None;
+                    for attempt in 0..=spawn_retries {
+                        let spawn_time = timer.elapsed().to_nanos();
+                        let specs = cluster.prepare(&joins).expect("failed to prepare new processes");
+                        let processes = specs.iter().map(|spec| spec.process).collect::<Vec<_>>();
+                        let expected_peers = old_peers + cluster.pending_workers();
+                        let mut failure = None;
+                        for spec in specs.iter() {
+                            match launcher.launch(spec, Path::new(&run_dir2)) {
+                                Ok(child) => supervisor.add(spec.process, child, Some(launcher::stderr_path(Path::new(&run_dir2), spec.process))),
+                                Err(e) => {
+                                    failure = Some((spec.process, format!("failed to launch: {}", e)));
+                                    break;
+                                },
+                            }
+                        }
+                        if failure.is_none() {
+                            // wait for all the new workers to join the cluster
+                            let deadline = Instant::now() + join_timeout;
+                            failure = loop {
+                                if worker.peers() >= expected_peers {
+                                    break None;
+                                }
+                                if Instant::now() >= deadline {
+                                    // report the first process whose workers did not arrive
+                                    let arrived = (worker.peers() - old_peers) / w;
+                                    break Some((processes[arrived.min(processes.len() - 1)], format!("did not join within {:?}", join_timeout)));
+                                }
+                                worker.step();
+                                if last_poll.elapsed() >= SUPERVISOR_POLL_INTERVAL {
+                                    last_poll = Instant::now();
+                                    if let Some(failure) = check_supervised(&mut supervisor, &processes).into_iter().next() {
+                                        eprint!("{}", failure);
+                                        break Some((failure.process, failure.status));
+                                    }
+                                }
+                            };
+                        }
+                        match failure {
+                            None => {
+                                joined = Some((processes, spawn_time));
+                                break;
+                            },
+                            Some((process, reason)) => {
+                                for &process in processes.iter() {
+                                    supervisor.kill(process);
+                                }
+                                if worker.peers() != old_peers {
+                                    eprintln!("process {} failed after {} of the {} new workers joined, aborting: {}",
+                                              process, worker.peers() - old_peers, expected_peers - old_peers, reason);
+                                    supervisor.kill_all();
+                                    std::process::exit(1);
+                                }
+                                eprintln!("spawn attempt {} of process {} failed: {}", attempt, process, reason);
+                                spawn_failures.push((process, attempt, spawn_time, reason));
+                            },
+                        }
+                    }
+                    if let Some((processes, spawn_time)) = joined {
+                        let peers_changed = timer.elapsed().to_nanos();
+                        for &process in processes.iter() {
+                            bootstrap_phases.push((process, "spawn", spawn_time));
+                            bootstrap_phases.push((process, "peers_changed", peers_changed));
+                        }
 
-                    control_sequence += 1;
+                        // bootstrap all the new workers with a single configuration
+                        let new_workers = cluster.joined();
+                        let count: usize = new_workers.iter().map(|workers| workers.len()).sum();
+                        new_workers.iter().zip(joins.iter())
+                            .flat_map(|(workers, &join)| workers.clone().map(move |new_worker| ControlInst::Bootstrap(join, new_worker)))
+                            .map(|cmd| Control::new(control_sequence, count, cmd))
+                            .for_each(|ctrl| control_input.as_mut().unwrap().send(ctrl));
+                        let bootstrap_sent = timer.elapsed().to_nanos();
+                        for &process in processes.iter() {
+                            bootstrap_phases.push((process, "bootstrap_sent", bootstrap_sent));
+                        }
 
-                    assert!(spawn_info.is_none());
-                    spawn_info = Some((p, elapsed_ns));
+                        control_sequence += 1;
 
-                    p += 1;
-                    nn += 1;
-                    join += 1;
-                    join %= worker.peers();
+                        join_servers.extend(processes.iter().cloned().zip(joins.iter().cloned()));
+                        assert!(spawn_info.is_none());
+                        spawn_info = Some((processes, new_workers.into_iter().flatten().collect::<Vec<_>>(), spawn_time));
+                    } else {
+                        // no worker arrived: skip this rescale without sending its controls
+                        eprintln!("giving up on this scale-out after {} attempts", spawn_retries + 1);
+                    }
                 }
             }
 
             let mut bin_moved = false;
-            if let Some((new_process, bootstrap_time)) = spawn_info {
+            if let Some((new_processes, new_workers, bootstrap_time)) = spawn_info.clone() {
                 if elapsed_ns > bootstrap_time + WORKER_BOOTSTRAP_MARGIN {
-                    // move about 1/peers of the bins to the new `w` workers
-                    let new_workers = (new_process*w..new_process*w+w).collect::<Vec<_>>();
-                    let moves = load_balancer.add_workers(new_workers).map(|(bin, to)| ControlInst::Move(BinId::new(bin), to)).collect::<Vec<_>>();
-                    let count = moves.len();
-                    moves
-                        .into_iter()
-                        .map(|mv| Control::new(control_sequence, count, mv))
-                        .for_each(|ctrl| control_input.as_mut().unwrap().send(ctrl));
-
-                    control_sequence += 1;
+                    // one plan for all the new workers: every new worker gets about 1/peers of the bins
+                    match placement {
+                        Placement::Topology => load_balancer.set_topology(cluster.topology()),
+                        Placement::Records => load_balancer.set_bin_loads(bin_records.clone()),
+                        Placement::Flat => {},
+                    }
+                    let snapshot = load_balancer.clone();
+                    let moves = load_balancer.add_workers(new_workers).collect::<Vec<_>>();
+                    plan_sizes = migration::bin_sizes(state_bytes, &bin_records);
+                    plan_traffic.push((elapsed_ns, plan_traffic_of(&snapshot, &load_balancer, &cluster.topology(), &plan_sizes)));
+                    let waves = migration::split_waves(moves, wave_limits, |bin| plan_sizes[bin]);
+                    migration = Some((MigrationSchedule::new(waves, pacing), snapshot, elapsed_ns + migration_deadline_ns));
                     bin_moved = true;
 
                     println!("bootstrap worker:\tbootstrap={}\tmoves={}", bootstrap_time, elapsed_ns);
                     spawn_metrics.push((bootstrap_time, elapsed_ns));
+                    for process in new_processes {
+                        bootstrap_phases.push((process, "moves_sent", elapsed_ns));
+                    }
                 }
             }
             if bin_moved { spawn_info = None; }
 
+            if let Some((rolled_back, time)) = compensation {
+                // The compensating configuration is applied like a wave, once the output frontier passed its time
+                if !probe.less_equal(&time) {
+                    rollbacks_applied.push((rolled_back, elapsed_ns));
+                    compensation = None;
+                }
+            }
+            if let (Some((schedule, snapshot, deadline)), Some(control_input)) = (migration.as_mut(), control_input.as_mut()) {
+                // A wave is applied once the output frontier passed the time of its configuration.
+                // Every wave gets its own timestamp, so that Megaphone applies them one after the other.
+                while let Some(wave) = schedule.next_wave(elapsed_ns, *control_input.time(), |time| !probe.less_equal(&time)) {
+                    let count = wave.len();
+                    let bytes = wave.iter().map(|&(bin, _)| plan_sizes[bin]).sum();
+                    wave.into_iter()
+                        .map(|(bin, to)| Control::new(control_sequence, count, ControlInst::Move(BinId::new(bin), to)))
+                        .for_each(|ctrl| control_input.send(ctrl));
+                    control_sequence += 1;
+                    migration_waves.push((elapsed_ns, count, bytes));
+                    last_config_time = Some(*control_input.time());
+                    let next_time = *control_input.time() + 1;
+                    control_input.advance_to(next_time);
+                }
+                if schedule.is_committed(|time| !probe.less_equal(&time)) {
+                    migration = None;
+                } else if elapsed_ns >= *deadline {
+                    // The plan was not applied in time: move the issued bins back to their previous workers
+                    // and restore the assignment of the load balancer, so that it matches the dataflow again
+                    let moves_back = schedule.rollback(|bin| snapshot.worker_of(bin).expect("bin without worker"));
+                    eprintln!("migration not acknowledged by its deadline, moving {} bins back", moves_back.len());
+                    let count = moves_back.len();
+                    moves_back.into_iter()
+                        .map(|(bin, to)| Control::new(control_sequence, count, ControlInst::Move(BinId::new(bin), to)))
+                        .for_each(|ctrl| control_input.send(ctrl));
+                    control_sequence += 1;
+                    rollbacks.push((elapsed_ns, count));
+                    compensation = Some((elapsed_ns, *control_input.time()));
+                    last_config_time = Some(*control_input.time());
+                    let next_time = *control_input.time() + 1;
+                    control_input.advance_to(next_time);
+                    load_balancer.restore(migration.take().unwrap().1);
+                }
+            }
+
+            if let Some(status_input) = status_input.as_mut() {
+                if status_enabled && elapsed_ns >= last_status.0 + status_interval_ns {
+                    let time = *status_input.time();
+                    status_input.send(status_request);
+                    status_windows.insert(status_request, (elapsed_ns, last_status.1));
+                    status_request += 1;
+                    last_status = (elapsed_ns, time);
+                }
+            }
+            for status in statuses.borrow_mut().drain(..) {
+                let (issued, since) = status_windows.remove(&status.request).expect("status of an unknown request");
+                let assignment = load_balancer.assignment();
+                let differences = status.owners.iter().zip(assignment.iter())
+                    .filter(|(owner, assigned)| owner.map(|o| o != **assigned).unwrap_or(false))
+                    .count();
+                // The observed workers are only valid if no bin moved in the interval the request covers,
+                // and the assignment is only comparable if no plan was started since
+                let settled = last_config_time.map(|t| t <= since).unwrap_or(true) && migration.is_none() && spawn_info.is_none();
+                let reconciled = reconcile && settled && differences > 0;
+                if reconciled {
+                    eprintln!("assignment differs from the dataflow on {} bins, adopting the observed workers", differences);
+                    load_balancer.reconcile(&status.owners);
+                }
+                bin_statuses.push((issued, status.observed(), status.conflicts.len(), differences, reconciled));
+                for (total, records) in bin_records.iter_mut().zip(status.records.iter()) {
+                    *total += records;
+                }
+                bin_loads.push((issued, status.records));
+            }
+
             output_metric_collector.acknowledge_while(
                 elapsed_ns,
                 |t| {
-                    !probe.less_than(&(t as usize)) // TODO(lorenzo) +1 ?
+                    match load {
+                        // records are stamped with their intended send time `t`: wait until it is complete
+                        Load::Open => !probe.less_equal(&(t as usize)),
+                        Load::Closed => !probe.less_than(&(t as usize)), // TODO(lorenzo) +1 ?
+                    }
                 });
 
             if input.is_none() {
@@ -272,21 +878,50 @@ fn main() {
 
             if elapsed_ns < duration_ns {
                 let input = input.as_mut().unwrap();
+                let keeps_up = match load {
+                    Load::Open => true,
+                    Load::Closed => {
+                        // if delta to probe is smaller than half of delta to production, consider to produce more data
+                        let probe_time = probe.with_frontier(|f| if f.is_empty() { 0 } else { f[0] }) as u64;
+                        let delta_probe = elapsed_ns.saturating_sub(probe_time);
+                        let delta_production = elapsed_ns - last_tick;
+                        delta_probe <= delta_production * 2
+                    },
+                };
+                if keeps_up && schedule.between(last_tick, elapsed_ns).next().is_some() {
+                    for (_, t) in schedule.between(last_tick, elapsed_ns) {
+                        let bucket = (t / TIMELINE_BUCKET_NS) as usize;
+                        if produced.len() <= bucket { produced.resize(bucket + 1, 0); }
+                        produced[bucket] += words_per_line as u64;
+                    }
+                    input.send((last_tick, elapsed_ns, worker.peers()));
+                    last_tick = elapsed_ns;
+                }
                 input.advance_to(elapsed_ns as usize);
                 if let Some(control_input) = control_input.as_mut() {
                     if *control_input.time() < elapsed_ns as usize {
                         control_input.advance_to(elapsed_ns as usize);
                     }
                 }
+                status_input.as_mut().unwrap().advance_to(elapsed_ns as usize);
             } else {
+                if let Some((schedule, _, _)) = migration.take() {
+                    // The plan neither committed nor hit its deadline before the input ended
+                    eprintln!("input ended with an open migration plan, {} waves not issued", schedule.remaining());
+                    uncommitted.push((elapsed_ns, schedule.issued(), schedule.remaining()));
+                }
                 input.take().unwrap();
                 control_input.take();
+                status_input.take();
             }
 
             if input.is_some() {
                 worker.step();
             } else {
-                while worker.step() { }
+                // the handler only sets a flag: a run that does not drain must still stop on Ctrl-C
+                while worker.step() {
+                    exit_if_interrupted(&mut supervisor);
+                }
             }
         }
 
@@ -295,28 +930,160 @@ fn main() {
             println!("count_ccdf\t{}\t{}\t{}", value, prob, count);
         }
 
-        if index == 0 { Some((output_metric_collector.into_inner(), spawn_metrics)) } else { None }
+        if index == 0 {
+            // Phases observed by the joined workers, relative to worker 0's timer. A process completed
+            // a phase when all of its workers did, except for `first_record` which is its first worker to do so.
+            let mut process_phases: HashMap<(usize, usize), u64> = HashMap::new();
+            for &(worker, phase_index, time) in joined_phases.borrow().iter() {
+                let time = time.saturating_sub(epoch);
+                let process = cluster.process_of(worker).expect("phase reported by an unknown worker");
+                let entry = process_phases.entry((process, phase_index)).or_insert(time);
+                *entry = ::std::cmp::max(*entry, time);
+            }
+            for (&worker, &time) in first_report.borrow().iter().filter(|(worker, _)| **worker >= peers) {
+                let process = cluster.process_of(worker).expect("records reported by an unknown worker");
+                let entry = process_phases.entry((process, phase("first_record"))).or_insert(time);
+                *entry = ::std::cmp::min(*entry, time);
+            }
+            bootstrap_phases.extend(process_phases.into_iter().map(|((process, phase_index), time)| (process, BOOTSTRAP_PHASES[phase_index], time)));
+            bootstrap_phases.sort_by_key(|&(process, name, _)| (process, phase(name)));
 
-    }).expect("unsuccessful execution").join().into_iter().map(|x| x.unwrap()).collect();
+            let ::streaming_harness::timeline::Timeline { timeline, .. } = output_metric_collector.into_inner();
+            Some(Measurements {
+                summary_timeline: ::streaming_harness::format::format_summary_timeline("summary_timeline".to_string(), timeline),
+                spawn_metrics,
+                produced,
+                processed: processed.borrow().clone(),
+                worker_timelines: worker_timelines.borrow().clone(),
+                bootstrap_phases,
+                state_samples: state_samples.borrow().clone(),
+                supervisor,
+                spawn_failures,
+                join_servers,
+                migration_waves,
+                rollbacks,
+                rollbacks_applied,
+                uncommitted,
+                bin_statuses,
+                plan_traffic,
+                bin_loads,
+            })
+        } else {
+            None
+        }
 
-    let result: Vec<(streaming_harness::timeline::Timeline<_,_,_,_>, Vec<_>)> = timelines.into_iter().filter_map(|mut x| x.take()).collect();
+    }).expect("unsuccessful execution").join().into_iter().map(|x| x.unwrap()).collect();
 
-    if !result.is_empty() {
-        let (timelines, spawn_metrics): (Vec<streaming_harness::timeline::Timeline<_,_,_,_>>, Vec<_>) = result.into_iter().unzip();
-        let ::streaming_harness::timeline::Timeline { timeline, .. } = ::streaming_harness::output::combine_all(timelines);
+    // only worker 0 produces input, measures spawning new processes and collects the per-worker timelines
+    let measurements = timelines.into_iter().filter_map(|mut x| x.take()).next();
 
-        let spawn_metrics = spawn_metrics.first().unwrap(); // only worker 0 measures spawn new processes
+    if let Some(Measurements { summary_timeline, spawn_metrics, produced, processed, worker_timelines, bootstrap_phases, state_samples, mut supervisor, spawn_failures, join_servers, migration_waves, rollbacks, rollbacks_applied, uncommitted, bin_statuses, plan_traffic, bin_loads }) = measurements {
+        // the spawned processes terminate with the computation, give them some time to exit cleanly
+        let failures: Vec<Failure> = supervisor.shutdown(SHUTDOWN_GRACE);
+        for failure in failures.iter() {
+            eprint!("{}", failure);
+        }
 
-        println!("{}", ::streaming_harness::format::format_summary_timeline("summary_timeline".to_string(), timeline.clone()));
+        println!("{}", summary_timeline);
         for (bootstrap, mv) in spawn_metrics.iter() {
             println!("spawn_metric\t{}\t{}", bootstrap, mv);
         }
 
-        let mut metrics = File::create("metrics").unwrap();
-        metrics.write(::streaming_harness::format::format_summary_timeline("summary_timeline".to_string(), timeline.clone()).as_bytes()).unwrap();
-        metrics.write(b"\n").unwrap();
+        let n = std::env::var("N").unwrap_or_default();
+        let w = std::env::var("W").unwrap_or_default();
+
+        let mut metadata = Metadata::new();
+        metadata.set("binary", "benchmark");
+        metadata.set("mode", mode);
+        metadata.set("load", load);
+        metadata.set("rate", rate);
+        metadata.set("duration_ns", duration_ns);
+        metadata.set("key_space", key_space);
+        metadata.set("words_per_line", words_per_line);
+        metadata.set("word_length", word_length);
+        metadata.set("validate", validate);
+        metadata.set("n", n);
+        metadata.set("w", w);
+        metadata.set("bins", 1 << BIN_SHIFT);
+        metadata.set("rescale_schedule_ns", spawn_schedule.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(","));
+        metadata.set("bootstrap_margin_ns", WORKER_BOOTSTRAP_MARGIN);
+        metadata.set("sample_state", sample_state);
+        metadata.set("launcher", launcher::from_spec(&launcher_spec).map(|l| l.describe()).unwrap_or(launcher_spec));
+        metadata.set("run_dir", run_dir);
+        metadata.set("hostfile", hostfile_path.unwrap_or_else(|| "-".to_string()));
+        metadata.set("join_timeout_s", join_timeout.as_secs());
+        metadata.set("spawn_retries", spawn_retries);
+        metadata.set("join_policy", join_policy);
+        metadata.set("spawn_batch", spawn_batch);
+        metadata.set("migration", strategy);
+        metadata.set("wave_bins", wave_limits.max_bins.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string()));
+        metadata.set("wave_bytes", wave_limits.max_bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string()));
+        metadata.set("pacing", pacing);
+        metadata.set("state_bytes_estimate", state_bytes);
+        metadata.set("migration_deadline_ns", migration_deadline_ns);
+        metadata.set("status_interval_ns", status_interval_ns);
+        metadata.set("reconcile", reconcile);
+        metadata.set("capacities", if capacities_spec.is_empty() { "-".to_string() } else { capacities_spec });
+        metadata.set("bin_placement", placement);
+        metadata.collect_environment();
+
+        let mut metrics = MetricsWriter::create(&metrics_path, &metadata).expect("failed to create metrics file");
+        metrics.raw(&summary_timeline).unwrap();
         for (bootstrap, mv) in spawn_metrics.iter() {
-            metrics.write(format!("spawn_metric\t{}\t{}\n", bootstrap, mv).as_bytes()).unwrap();
+            metrics.record("spawn_metric", &[bootstrap, mv]).unwrap();
+        }
+        for (bucket, records) in processed.iter().enumerate() {
+            metrics.record("throughput", &[bucket as u64 * TIMELINE_BUCKET_NS, *records]).unwrap();
+        }
+        for (bucket, records) in produced.iter().enumerate() {
+            metrics.record("offered_load", &[bucket as u64 * TIMELINE_BUCKET_NS, *records]).unwrap();
+        }
+        worker_timelines.write(&mut metrics).unwrap();
+        for (process, phase, time) in bootstrap_phases.iter() {
+            metrics.record("bootstrap_phase", &[process.to_string(), phase.to_string(), time.to_string()]).unwrap();
+        }
+        for &(worker, time, entries, updates, pending) in state_samples.iter() {
+            metrics.record("state_size", &[worker as u64, time, entries as u64, updates, pending as u64]).unwrap();
+        }
+        for (time, bins, bytes) in migration_waves.iter() {
+            metrics.record("migration_wave", &[time, &(*bins as u64), bytes]).unwrap();
+        }
+        for (time, moves) in rollbacks.iter() {
+            metrics.record("migration_rollback", &[*time, *moves as u64]).unwrap();
+        }
+        for (rolled_back, applied) in rollbacks_applied.iter() {
+            metrics.record("rollback_applied", &[rolled_back, applied]).unwrap();
+        }
+        for &(time, issued, remaining) in uncommitted.iter() {
+            metrics.record("migration_uncommitted", &[time, issued as u64, remaining as u64]).unwrap();
+        }
+        for &(time, observed, conflicts, differences, reconciled) in bin_statuses.iter() {
+            metrics.record("bin_status", &[time, observed as u64, conflicts as u64, differences as u64, reconciled as u64]).unwrap();
+        }
+        // the per-bin load trace replayed by the `simulate` binary
+        for (time, records) in bin_loads.iter() {
+            for (bin, count) in records.iter().enumerate() {
+                metrics.record("bin_load", &[*time, bin as u64, *count]).unwrap();
+            }
+        }
+        for (time, traffic) in plan_traffic.iter() {
+            let (intra, cross, hosts) = (traffic.intra_process, traffic.cross_process, traffic.cross_host);
+            metrics.record("plan_traffic", &[*time, intra.bins as u64, intra.bytes, cross.bins as u64, cross.bytes, hosts.bins as u64, hosts.bytes]).unwrap();
+        }
+        for (process, server) in join_servers.iter() {
+            metrics.record("join_server", &[process, server]).unwrap();
+        }
+        for (process, attempt, time, reason) in spawn_failures.iter() {
+            metrics.record("spawn_failure", &[process.to_string(), attempt.to_string(), time.to_string(), reason.clone()]).unwrap();
+        }
+        for failure in failures.iter() {
+            metrics.record("process_failure", &[failure.process.to_string(), failure.status.clone()]).unwrap();
+        }
+        metrics.finish().unwrap();
+        println!("metrics written to {}", metrics_path);
+
+        if !failures.is_empty() {
+            std::process::exit(1);
         }
     }
 }
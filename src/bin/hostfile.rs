@@ -0,0 +1,48 @@
+//! Manage the timely hostfile of a cluster running on the local machine.
+//!
+//! rescaling-examples $ cargo run --bin hostfile -- create hosts 2
+//! rescaling-examples $ cargo run --bin hostfile -- add hosts [HOST]
+//!
+//! `create` writes the addresses of the initial processes, `add` appends the address of a joining
+//! process on `HOST` (default 127.0.0.1). Local addresses get a free port, so that several clusters
+//! can run side by side.
+
+extern crate clap;
+
+use clap::{App, Arg, SubCommand};
+
+use rescaling_examples::launcher::Hostfile;
+
+fn main() {
+    let matches = App::new("hostfile")
+        .about("Create and extend a hostfile with free local ports")
+        .subcommand(SubCommand::with_name("create")
+            .about("write a hostfile for the initial processes")
+            .arg(Arg::with_name("PATH").required(true))
+            .arg(Arg::with_name("PROCESSES").required(true)))
+        .subcommand(SubCommand::with_name("add")
+            .about("append the address of a joining process, printing its index")
+            .arg(Arg::with_name("PATH").required(true))
+            .arg(Arg::with_name("HOST").help("host of the joining process (default 127.0.0.1)")))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("create", Some(matches)) => {
+            let path = matches.value_of("PATH").unwrap();
+            let processes = matches.value_of("PROCESSES").unwrap().parse().expect("invalid number of processes");
+            Hostfile::local(path, processes).unwrap_or_else(|e| panic!("failed to write {}: {}", path, e));
+        },
+        ("add", Some(matches)) => {
+            let path = matches.value_of("PATH").unwrap();
+            let mut hostfile = Hostfile::read(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+            let process = hostfile.addresses().len();
+            let address = hostfile.next_address(matches.value_of("HOST").unwrap_or("127.0.0.1")).expect("failed to find a free port");
+            hostfile.set(process, address).unwrap_or_else(|e| panic!("failed to write {}: {}", path, e));
+            println!("{}", process);
+        },
+        _ => {
+            eprintln!("{}", matches.usage());
+            std::process::exit(1);
+        },
+    }
+}
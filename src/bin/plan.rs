@@ -0,0 +1,82 @@
+//! Preview a rebalancing plan without issuing it.
+//!
+//! rescaling-examples $ cargo run --bin plan -- --workers 2 --add 1
+//!
+//! Shows the bin→worker map before and after adding `--add` workers to a cluster of `--workers`
+//! workers, the bins moved per worker, the estimated state to move and the resulting imbalance.
+//! The current map is the initial round-robin layout, or the one given with `--map` (e.g. after a
+//! `map` control command); `--print-map` prints it comma-separated, in the format of `--map`, and exits.
+//! `--capacities WORKER:WEIGHT,...` sets capacity weights, also of the added
+//! workers; with `--add 0` it previews the moves of a capacity change. `--threads T` groups the
+//! current workers into processes of `T` workers and the added ones into a new process: the plan then
+//! prefers moves within a process and keeps neighbouring bins in the same process, and its traffic
+//! within and across processes is shown.
+
+extern crate clap;
+
+use clap::{App, Arg};
+
+use dynamic_scaling_mechanism::BIN_SHIFT;
+use rescaling_examples::{parse_capacities, LoadBalancer};
+use rescaling_examples::migration::bin_bytes;
+use rescaling_examples::placement::Topology;
+
+fn main() {
+    let matches = App::new("plan")
+        .about("Preview the bin moves of adding workers to a cluster")
+        .arg(Arg::with_name("workers").long("workers").takes_value(true).required(true).help("current number of workers"))
+        .arg(Arg::with_name("add").long("add").takes_value(true).default_value("1").help("number of workers to add"))
+        .arg(Arg::with_name("bins").long("bins").takes_value(true).help("number of bins (default: the Megaphone configuration)"))
+        .arg(Arg::with_name("map").long("map").takes_value(true).conflicts_with("bins")
+            .help("current worker of every bin, comma-separated (default: round-robin)"))
+        .arg(Arg::with_name("print-map").long("print-map").help("print the current map in the format of --map and exit"))
+        .arg(Arg::with_name("capacities").long("capacities").takes_value(true)
+            .help("capacity weights as WORKER:WEIGHT,... (default 1), bins are balanced in proportion to them"))
+        .arg(Arg::with_name("threads").long("threads").takes_value(true)
+            .help("worker threads per current process, the added workers form a new process"))
+        .arg(Arg::with_name("key-space").long("key-space").takes_value(true).default_value("1000")
+            .help("number of distinct keys, spread evenly over the bins to estimate the state of a bin"))
+        .get_matches();
+
+    let workers: usize = matches.value_of("workers").unwrap().parse().expect("invalid number of workers");
+    let add: usize = matches.value_of("add").unwrap().parse().expect("invalid number of workers to add");
+    let map: Option<Vec<usize>> = matches.value_of("map").map(|m| m.split(',').map(|w| w.trim().parse().expect("invalid worker in map")).collect());
+    let bins: usize = match (matches.value_of("bins"), map.as_ref()) {
+        (_, Some(map)) => map.len(),
+        (Some(b), None) => b.parse().expect("invalid number of bins"),
+        (None, None) => 1 << BIN_SHIFT,
+    };
+    let key_space: u64 = matches.value_of("key-space").unwrap().parse().expect("invalid key space");
+
+    let mut load_balancer = match map {
+        Some(map) => LoadBalancer::from_map((0..workers).collect(), map),
+        None => LoadBalancer::new((0..workers).collect(), bins),
+    };
+    if matches.is_present("print-map") {
+        println!("{}", load_balancer.assignment().iter().map(|w| w.to_string()).collect::<Vec<_>>().join(","));
+        return;
+    }
+    let capacities = matches.value_of("capacities").map(|c| parse_capacities(c).unwrap_or_else(|e| panic!("{}", e))).unwrap_or_default();
+    for (worker, capacity) in capacities {
+        load_balancer.set_capacity(worker, capacity);
+    }
+    let threads: Option<usize> = matches.value_of("threads").map(|t| t.parse().expect("invalid number of threads"));
+    let topology = threads.map(|threads| {
+        assert!(threads > 0, "--threads must be positive");
+        let mut processes = (0..workers).step_by(threads).map(|first| first..(first + threads).min(workers)).collect::<Vec<_>>();
+        if add > 0 {
+            processes.push(workers..workers + add);
+        }
+        let hosts = vec!["localhost".to_string(); processes.len()];
+        Topology::new(processes, hosts)
+    });
+    if let Some(topology) = topology.clone() {
+        load_balancer.set_topology(topology);
+    }
+    let bytes = bin_bytes(key_space, bins);
+    let preview = load_balancer.preview((workers..workers + add).collect(), |_bin| bytes);
+    print!("{}", preview);
+    if let Some(topology) = topology {
+        print!("{}", preview.traffic(&topology));
+    }
+}
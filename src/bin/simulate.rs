@@ -0,0 +1,125 @@
+//! Replay a per-bin load trace against load balancing policies, without running a dataflow.
+//!
+//! rescaling-examples $ cargo run --bin simulate -- --processes 2 --threads 1 --rescale 13,26 --skew 1
+//! rescaling-examples $ cargo run --bin simulate -- --trace metrics
+//!
+//! With `--trace`, the trace is the `bin_load` records of a benchmark metrics file, and the cluster
+//! and rescale times default to the ones of the recorded run. Otherwise a synthetic trace of `--rate`
+//! records per second is generated, Zipf-distributed over the bins with exponent `--skew`. Every
+//! rescale adds `--spawn-batch` processes of `--threads` workers; every policy of `--policy`
+//! plans them, and the moves, the estimated state they migrate and the load imbalance of the
+//! workers before and after every rescale are reported. `flat` and `topology` ignore the trace
+//! and mostly agree on a single host; `records` chooses the moved bins by the records so far.
+
+extern crate clap;
+
+use clap::{App, Arg, ArgMatches};
+
+use dynamic_scaling_mechanism::BIN_SHIFT;
+use rescaling_examples::parse_capacities;
+use rescaling_examples::analysis::Run;
+use rescaling_examples::metrics::{Metadata, MetricsFile};
+use rescaling_examples::migration::bin_bytes;
+use rescaling_examples::placement::Placement;
+use rescaling_examples::simulation::{simulate, Scenario, Trace};
+
+/// The value of argument `name`, else of metadata `key` of the recorded run, else `default`.
+fn value_or<T: std::str::FromStr>(matches: &ArgMatches, name: &str, metadata: Option<&Metadata>, key: &str, default: T) -> T where T::Err: std::fmt::Debug {
+    match matches.value_of(name).or_else(|| metadata.and_then(|m| m.get(key))) {
+        Some(value) => value.parse().unwrap_or_else(|e| panic!("invalid {}: {:?}", name, e)),
+        None => default,
+    }
+}
+
+fn percent(imbalance: Option<f64>) -> String {
+    imbalance.map(|i| format!("{:.1}%", i * 100.)).unwrap_or_else(|| "-".to_string())
+}
+
+fn main() {
+    let matches = App::new("simulate")
+        .about("Replay a per-bin load trace against load balancing policies")
+        .arg(Arg::with_name("trace").long("trace").takes_value(true)
+            .help("benchmark metrics file with bin_load records (default: a synthetic trace)"))
+        .arg(Arg::with_name("processes").long("processes").takes_value(true).help("initial processes (default 2)"))
+        .arg(Arg::with_name("threads").long("threads").takes_value(true).help("worker threads per process (default 1)"))
+        .arg(Arg::with_name("spawn-batch").long("spawn-batch").takes_value(true).help("processes added by every rescale (default 1)"))
+        .arg(Arg::with_name("rescale").long("rescale").takes_value(true)
+            .help("rescale times in seconds, comma-separated (default: the recorded ones, or 1/3 and 2/3 of the duration)"))
+        .arg(Arg::with_name("capacities").long("capacities").takes_value(true)
+            .help("capacity weights as WORKER:WEIGHT,... (default 1), bins are balanced in proportion to them"))
+        .arg(Arg::with_name("policy").long("policy").takes_value(true).default_value("flat,topology,records")
+            .help("policies to compare, comma-separated: flat, topology, records"))
+        .arg(Arg::with_name("key-space").long("key-space").takes_value(true).help("number of distinct keys, to estimate the state of a bin (default 1000)"))
+        .arg(Arg::with_name("bins").long("bins").takes_value(true).conflicts_with("trace")
+            .help("number of bins of the synthetic trace (default: the Megaphone configuration)"))
+        .arg(Arg::with_name("duration").long("duration").takes_value(true).default_value("40")
+            .help("duration of the synthetic trace in seconds"))
+        .arg(Arg::with_name("interval").long("interval").takes_value(true).default_value("5")
+            .help("sampling interval of the synthetic trace in seconds"))
+        .arg(Arg::with_name("rate").long("rate").takes_value(true).default_value("100000")
+            .help("records per second of the synthetic trace"))
+        .arg(Arg::with_name("skew").long("skew").takes_value(true).default_value("0")
+            .help("Zipf exponent of the per-bin load of the synthetic trace, 0 is uniform"))
+        .arg(Arg::with_name("seed").long("seed").takes_value(true).default_value("0"))
+        .get_matches();
+
+    let recorded = matches.value_of("trace").map(|path| MetricsFile::read(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e)));
+    let metadata = recorded.as_ref().map(|metrics| &metrics.metadata);
+
+    let (trace, recorded_rescales) = match recorded.as_ref() {
+        Some(metrics) => {
+            let trace = Trace::from_metrics(metrics).unwrap_or_else(|e| panic!("invalid trace: {}", e));
+            let run = Run::from_metrics(metrics).unwrap_or_else(|e| panic!("invalid metrics file: {}", e));
+            (trace, run.events.iter().map(|event| event.moved).collect())
+        },
+        None => {
+            let seconds = |name: &str| -> u64 { matches.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|e| panic!("invalid {}: {}", name, e)) * 1_000_000_000 };
+            let duration_ns = seconds("duration");
+            let bins = matches.value_of("bins").map(|b| b.parse().expect("invalid number of bins")).unwrap_or(1 << BIN_SHIFT);
+            let rate = matches.value_of("rate").unwrap().parse().expect("invalid rate");
+            let skew = matches.value_of("skew").unwrap().parse().expect("invalid skew");
+            let seed = matches.value_of("seed").unwrap().parse().expect("invalid seed");
+            (Trace::synthetic(bins, duration_ns, seconds("interval"), rate, skew, seed), vec![duration_ns / 3, 2 * duration_ns / 3])
+        },
+    };
+
+    let processes: usize = value_or(&matches, "processes", metadata, "n", 2);
+    let threads: usize = value_or(&matches, "threads", metadata, "w", 1);
+    let spawn_batch: usize = value_or(&matches, "spawn-batch", metadata, "spawn_batch", 1);
+    let key_space: u64 = value_or(&matches, "key-space", metadata, "key_space", 1000);
+    let mut rescales: Vec<u64> = match matches.value_of("rescale") {
+        Some(times) => times.split(',').map(|t| t.trim().parse::<f64>().map(|s| (s * 1e9) as u64).expect("invalid rescale time")).collect(),
+        None => recorded_rescales,
+    };
+    rescales.sort();
+    // capacities "-" in the metadata of a run without capacities
+    let capacities = matches.value_of("capacities").or_else(|| metadata.and_then(|m| m.get("capacities")).filter(|c| *c != "-"))
+        .map(|c| parse_capacities(c).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let policies = matches.value_of("policy").unwrap().split(',')
+        .map(|p| p.trim().parse::<Placement>().unwrap_or_else(|e| panic!("{}", e)))
+        .collect::<Vec<_>>();
+
+    let scenario = Scenario {
+        processes,
+        threads,
+        rescales: rescales.into_iter().map(|time| (time, spawn_batch)).collect(),
+        capacities,
+        state_bytes: bin_bytes(key_space, trace.bins) * trace.bins as u64,
+    };
+    println!("{} samples of {} bins, {} records, {} processes of {} workers, {} rescales",
+             trace.samples.len(), trace.bins, trace.samples.iter().map(|(_, r)| r.iter().sum::<u64>()).sum::<u64>(),
+             processes, threads, scenario.rescales.len());
+
+    for policy in policies {
+        let simulation = simulate(&trace, &scenario, policy);
+        println!("policy {}: mean imbalance {}, {} moves, ~{} bytes moved", policy, percent(simulation.imbalance),
+                 simulation.rescales.iter().map(|r| r.moves).sum::<usize>(), simulation.rescales.iter().map(|r| r.bytes()).sum::<u64>());
+        for rescale in simulation.rescales.iter() {
+            println!("  rescale at {:.1}s: {} workers, {} moves (~{} bytes; {} within processes, {} across processes, {} across hosts), imbalance {} -> {}",
+                     rescale.time as f64 / 1e9, rescale.workers, rescale.moves, rescale.bytes(),
+                     rescale.traffic.intra_process.bins, rescale.traffic.cross_process.bins, rescale.traffic.cross_host.bins,
+                     percent(rescale.imbalance_before), percent(rescale.imbalance_after));
+        }
+    }
+}
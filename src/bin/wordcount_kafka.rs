@@ -30,6 +30,7 @@ use timely::dataflow::operators::broadcast::Broadcast;
 use timely::dataflow::operators::exchange::Exchange;
 use colored::Colorize;
 use rescaling_examples::verify;
+use rescaling_examples::status::bin_status;
 use std::cell::RefCell;
 
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -63,7 +64,8 @@ fn main() {
                             .collect::<Vec<_>>()
                     ).probe_with(&mut words_probe);
 
-            let control = rescaling_examples::kafka::control_stream(scope, words_probe, widx).broadcast();
+            let (control, status_requests) = rescaling_examples::kafka::control_stream(scope, words_probe, widx);
+            let control = control.broadcast();
 
             control.inspect(|c| println!("{}", format!("control message is {:?}", c).bold().yellow()));
 
@@ -76,6 +78,10 @@ fn main() {
                     .inspect(move |x| println!("[W{}] stateful seen: {:?}", widx, x))
                     .probe_with(&mut stateful_probe);
 
+            // answer the "status" messages with the bin map observed since the previous one
+            bin_status(&stateful_out, &status_requests.broadcast(), |(key, _)| calculate_hash(key))
+                .inspect(|status| print!("{}", format!("{}", status).bold().green()));
+
             let correct =
                 words_in
                     .state_machine(|key: &String, val, agg: &mut u64| {
@@ -5,13 +5,23 @@ use rdkafka::consumer::{BaseConsumer, Consumer, EmptyConsumerContext};
 
 use dynamic_scaling_mechanism::{Control, ControlInst, BinId};
 use timely::dataflow::operators::generic::source;
+use timely::dataflow::operators::Map;
 use rdkafka::message::Message;
 use colored::Colorize;
 
-/// Subscribe to the "megaphone-control" topic and return a stream of control commands
-pub fn control_stream<G: Scope<Timestamp=usize>>(scope: &mut G, input_probe: ProbeHandle<usize>, widx: usize) -> Stream<G, Control> {
+/// A message of the control topic
+#[derive(Clone)]
+enum Command {
+    Control(Control),
+    /// A status request, see `crate::status`
+    Status(u64),
+}
+
+/// Subscribe to the "megaphone-control" topic and return a stream of control commands and a stream
+/// of status requests (the "status" message)
+pub fn control_stream<G: Scope<Timestamp=usize>>(scope: &mut G, input_probe: ProbeHandle<usize>, widx: usize) -> (Stream<G, Control>, Stream<G, u64>) {
 
-    source(scope, "ControlStream", |mut cap, info| {
+    let commands = source(scope, "ControlStream", |mut cap, info| {
 
         let consumer = if widx == 0 { // Only worker 0 subscribes to the kafka topic
             let topic = "megaphone-control";
@@ -44,6 +54,7 @@ pub fn control_stream<G: Scope<Timestamp=usize>>(scope: &mut G, input_probe: Pro
         let activator = scope.activator_for(&info.address[..]);
 
         let mut seqno: u64 = 0;
+        let mut status_request: u64 = 0;
 
         move |output| {
             if let Some(consumer) = &consumer {
@@ -58,6 +69,14 @@ pub fn control_stream<G: Scope<Timestamp=usize>>(scope: &mut G, input_probe: Pro
 
                         // println!("text is {:?}", text);
 
+                        if text.trim().eq_ignore_ascii_case("status") {
+                            output.session(cap).give(Command::Status(status_request));
+                            status_request += 1;
+                            let new_time = *cap.time() + 1;
+                            cap.downgrade(&new_time);
+                            continue;
+                        }
+
                         let instructions = text.split(",").map(|text| {
                             let text = text.trim();
                             let tokens = text.split(" ").map(|x| x.to_lowercase().trim().to_string()).collect::<Vec<_>>();
@@ -87,10 +106,13 @@ pub fn control_stream<G: Scope<Timestamp=usize>>(scope: &mut G, input_probe: Pro
                         // if command has no syntax error, give it to the control stream
                         if instructions.iter().all(|x| x.is_some()) {
                             let count = instructions.len();
-                            let controls = instructions.into_iter().map(move |instr| Control::new(seqno, count, instr.clone().unwrap()));
+                            let controls = instructions.into_iter().map(move |instr| Command::Control(Control::new(seqno, count, instr.clone().unwrap())));
                             seqno += 1;
 
                             output.session(cap).give_iterator(controls);
+                            // every message is a configuration of its own, applied after the previous ones
+                            let new_time = *cap.time() + 1;
+                            cap.downgrade(&new_time);
                         }
                     }
 
@@ -110,5 +132,9 @@ pub fn control_stream<G: Scope<Timestamp=usize>>(scope: &mut G, input_probe: Pro
                 }
             }
         }
-    })
+    });
+
+    let controls = commands.flat_map(|command| match command { Command::Control(control) => Some(control), _ => None });
+    let requests = commands.flat_map(|command| match command { Command::Status(request) => Some(request), _ => None });
+    (controls, requests)
 }
@@ -0,0 +1,445 @@
+//! Starting new worker processes that join a running cluster.
+//!
+//! A `Launcher` turns a `ProcessSpec` (which process to start and how it joins the cluster)
+//! into a command. Launchers are selected with a spec string (see `from_spec`):
+//! * `exe`: the executable of the current process (default)
+//! * `binary:PATH`: a prebuilt binary
+//! * `template:CMD`: a command template, e.g. `template:ssh host ./benchmark {args}`
+//!
+//! A `Cluster` keeps track of the processes of a running cluster and of its `Hostfile`, so that
+//! new processes get the right arguments and an address on the host of their bootstrap server.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+use crate::placement::Topology;
+
+/// Process to start and how it joins the cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessSpec {
+    /// Initial number of processes of the cluster (`-n`)
+    pub processes: usize,
+    /// Worker threads of the new process (`-w`)
+    pub workers: usize,
+    /// Index of the new process (`-p`)
+    pub process: usize,
+    /// Worker used as bootstrap server (`--join`)
+    pub join: usize,
+    /// Number of processes once the new one joined (`--nn`)
+    pub new_processes: usize,
+    /// Addresses of the processes (`-h`), timely's default `localhost:2101+p` scheme if `None`
+    pub hostfile: Option<PathBuf>,
+    /// Host of the new process in the hostfile, `None` without a hostfile
+    pub host: Option<String>,
+}
+
+impl ProcessSpec {
+    /// Timely arguments for the new process.
+    pub fn timely_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-n".to_string(), self.processes.to_string(),
+            "-w".to_string(), self.workers.to_string(),
+            "-p".to_string(), self.process.to_string(),
+            "--join".to_string(), self.join.to_string(),
+            "--nn".to_string(), self.new_processes.to_string(),
+        ];
+        if let Some(hostfile) = self.hostfile.as_ref() {
+            args.push("-h".to_string());
+            args.push(hostfile.display().to_string());
+        }
+        args
+    }
+}
+
+/// A free local TCP port, as reported by the OS.
+pub fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// A timely hostfile: the address of process `p` on line `p`.
+#[derive(Debug, Clone)]
+pub struct Hostfile {
+    path: PathBuf,
+    addresses: Vec<String>,
+}
+
+impl Hostfile {
+    pub fn read<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let addresses = BufReader::new(File::open(&path)?).lines()
+            .filter(|line| line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+            .collect::<io::Result<_>>()?;
+        Ok(Hostfile { path, addresses })
+    }
+
+    /// Write a hostfile with `processes` local addresses on free ports.
+    pub fn local<P: Into<PathBuf>>(path: P, processes: usize) -> io::Result<Self> {
+        let mut hostfile = Hostfile { path: path.into(), addresses: Vec::new() };
+        for _ in 0..processes {
+            hostfile.addresses.push(format!("127.0.0.1:{}", free_port()?));
+        }
+        hostfile.write()?;
+        Ok(hostfile)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    /// The host of the address of `process`.
+    pub fn host(&self, process: usize) -> Option<&str> {
+        self.addresses.get(process).and_then(|address| address.rsplitn(2, ':').last())
+    }
+
+    /// An address on `host` for a new process: a free port on the local host, otherwise the port
+    /// following the highest one used on `host`, as free ports of another host cannot be probed.
+    pub fn next_address(&self, host: &str) -> io::Result<String> {
+        if host == "127.0.0.1" || host == "localhost" {
+            return Ok(format!("{}:{}", host, free_port()?));
+        }
+        let port = self.addresses.iter()
+            .filter(|address| address.rsplitn(2, ':').last() == Some(host))
+            .filter_map(|address| address.rsplit(':').next().and_then(|port| port.parse::<u16>().ok()))
+            .max()
+            .map(|port| port + 1)
+            .unwrap_or(2101);
+        Ok(format!("{}:{}", host, port))
+    }
+
+    /// Set the address of `process`, which is either an existing process or the next one.
+    pub fn set(&mut self, process: usize, address: String) -> io::Result<()> {
+        assert!(process <= self.addresses.len(), "hostfile has no entry for process {}", process - 1);
+        if process == self.addresses.len() {
+            self.addresses.push(address);
+        } else {
+            self.addresses[process] = address;
+        }
+        self.write()
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let mut file = File::create(&self.path)?;
+        for address in self.addresses.iter() {
+            writeln!(file, "{}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// The processes of a running cluster, used to describe the processes joining it.
+/// Every process has `threads` workers, the ones of process `p` are `p * threads..(p + 1) * threads`.
+pub struct Cluster {
+    /// Initial number of processes
+    processes: usize,
+    /// Worker threads of every process
+    threads: usize,
+    /// Current number of processes
+    size: usize,
+    /// Number of processes returned by `prepare`, until they joined
+    pending: usize,
+    hostfile: Option<Hostfile>,
+}
+
+impl Cluster {
+    /// A cluster started with `processes` processes of `threads` workers, using `hostfile` if any.
+    pub fn new(processes: usize, threads: usize, hostfile: Option<Hostfile>) -> Self {
+        Cluster { processes, threads, size: processes, pending: 0, hostfile }
+    }
+
+    /// Current number of processes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Current number of workers.
+    pub fn peers(&self) -> usize {
+        self.size * self.threads
+    }
+
+    /// Worker indices of `process`.
+    pub fn workers(&self, process: usize) -> Range<usize> {
+        process * self.threads..(process + 1) * self.threads
+    }
+
+    /// The process `worker` belongs to.
+    pub fn process_of(&self, worker: usize) -> Option<usize> {
+        if worker < self.peers() { Some(worker / self.threads) } else { None }
+    }
+
+    /// The next processes to join the cluster, one for every bootstrap server of `joins`. With a hostfile,
+    /// every process runs on the host of its bootstrap server and gets an address there (see
+    /// `Hostfile::next_address`). Until `joined` is called, the same processes are returned again
+    /// (with new ports), e.g. to retry a failed spawn.
+    pub fn prepare(&mut self, joins: &[usize]) -> io::Result<Vec<ProcessSpec>> {
+        self.pending = 0;
+        let mut specs = Vec::with_capacity(joins.len());
+        for &join in joins {
+            let process = self.size + self.pending;
+            let server = self.process_of(join);
+            let (hostfile, host) = match self.hostfile.as_mut() {
+                Some(hostfile) => {
+                    let host = server.and_then(|server| hostfile.host(server)).unwrap_or("127.0.0.1").to_string();
+                    let address = hostfile.next_address(&host)?;
+                    hostfile.set(process, address)?;
+                    (Some(hostfile.path().to_path_buf()), Some(host))
+                },
+                None => (None, None),
+            };
+            self.pending += 1;
+            specs.push(ProcessSpec {
+                processes: self.processes,
+                workers: self.threads,
+                process,
+                join,
+                new_processes: process + 1,
+                hostfile,
+                host,
+            });
+        }
+        Ok(specs)
+    }
+
+    /// The next process to join the cluster through worker `join`, see `prepare`.
+    pub fn next_process(&mut self, join: usize) -> io::Result<ProcessSpec> {
+        self.prepare(&[join]).map(|mut specs| specs.remove(0))
+    }
+
+    /// Number of workers of the processes returned by `prepare`.
+    pub fn pending_workers(&self) -> usize {
+        self.pending * self.threads
+    }
+
+    /// The workers of every process and the host of every process: the host of its hostfile address,
+    /// or the local host without a hostfile.
+    pub fn topology(&self) -> Topology {
+        let hosts = (0..self.size)
+            .map(|process| self.hostfile.as_ref().and_then(|hostfile| hostfile.host(process)).unwrap_or("localhost").to_string())
+            .collect();
+        Topology::new((0..self.size).map(|process| self.workers(process)).collect(), hosts)
+    }
+
+    /// Record that the processes returned by `prepare` joined the cluster, returning their worker indices.
+    pub fn joined(&mut self) -> Vec<Range<usize>> {
+        assert!(self.pending > 0, "no process is joining");
+        let first = self.size;
+        self.size += std::mem::take(&mut self.pending);
+        (first..self.size).map(|process| self.workers(process)).collect()
+    }
+}
+
+pub trait Launcher {
+    /// The command starting the process described by `spec`.
+    fn command(&self, spec: &ProcessSpec) -> Command;
+
+    /// Human readable description, recorded in the metrics metadata.
+    fn describe(&self) -> String;
+
+    /// Start the process described by `spec`, redirecting its output to
+    /// `process-{p}-stdout` and `process-{p}-stderr` in `log_dir`.
+    fn launch(&self, spec: &ProcessSpec, log_dir: &Path) -> io::Result<Child> {
+        fs::create_dir_all(log_dir)?;
+        let stdout = File::create(stdout_path(log_dir, spec.process))?;
+        let stderr = File::create(stderr_path(log_dir, spec.process))?;
+        self.command(spec).stdout(stdout).stderr(stderr).spawn()
+    }
+}
+
+/// File the stdout of `process` is redirected to by `Launcher::launch`.
+pub fn stdout_path(log_dir: &Path, process: usize) -> PathBuf {
+    log_dir.join(format!("process-{}-stdout", process))
+}
+
+/// File the stderr of `process` is redirected to by `Launcher::launch`.
+pub fn stderr_path(log_dir: &Path, process: usize) -> PathBuf {
+    log_dir.join(format!("process-{}-stderr", process))
+}
+
+/// Starts the executable of the current process, so that spawning does not pay for cargo.
+pub struct CurrentExe {
+    path: PathBuf,
+}
+
+impl CurrentExe {
+    pub fn new() -> io::Result<Self> {
+        Ok(CurrentExe { path: std::env::current_exe()? })
+    }
+}
+
+impl Launcher for CurrentExe {
+    fn command(&self, spec: &ProcessSpec) -> Command {
+        let mut command = Command::new(&self.path);
+        command.args(spec.timely_args());
+        command
+    }
+
+    fn describe(&self) -> String {
+        format!("exe:{}", self.path.display())
+    }
+}
+
+/// Starts a prebuilt binary.
+pub struct Binary {
+    path: PathBuf,
+}
+
+impl Binary {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Binary { path: path.into() }
+    }
+}
+
+impl Launcher for Binary {
+    fn command(&self, spec: &ProcessSpec) -> Command {
+        let mut command = Command::new(&self.path);
+        command.args(spec.timely_args());
+        command
+    }
+
+    fn describe(&self) -> String {
+        format!("binary:{}", self.path.display())
+    }
+}
+
+/// Runs a user-provided command. The template is split on whitespace and the placeholders
+/// `{n}`, `{w}`, `{p}`, `{join}`, `{nn}`, `{hostfile}` and `{host}` (`localhost` without a hostfile)
+/// are replaced in every token, while a
+/// `{args}` token is replaced with all the timely arguments.
+pub struct CommandTemplate {
+    template: String,
+}
+
+impl CommandTemplate {
+    pub fn new<S: Into<String>>(template: S) -> Result<Self, String> {
+        let template = template.into();
+        if template.split_whitespace().next().is_none() {
+            return Err("empty command template".to_string());
+        }
+        Ok(CommandTemplate { template })
+    }
+
+    /// The program and arguments of the command for `spec`.
+    pub fn expand(&self, spec: &ProcessSpec) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for token in self.template.split_whitespace() {
+            if token == "{args}" {
+                tokens.extend(spec.timely_args());
+            } else {
+                tokens.push(token
+                    .replace("{nn}", &spec.new_processes.to_string())
+                    .replace("{n}", &spec.processes.to_string())
+                    .replace("{w}", &spec.workers.to_string())
+                    .replace("{p}", &spec.process.to_string())
+                    .replace("{join}", &spec.join.to_string())
+                    .replace("{hostfile}", &spec.hostfile.as_ref().map(|h| h.display().to_string()).unwrap_or_default())
+                    .replace("{host}", spec.host.as_deref().unwrap_or("localhost")));
+            }
+        }
+        tokens
+    }
+}
+
+impl Launcher for CommandTemplate {
+    fn command(&self, spec: &ProcessSpec) -> Command {
+        let tokens = self.expand(spec);
+        let mut command = Command::new(&tokens[0]);
+        command.args(&tokens[1..]);
+        command
+    }
+
+    fn describe(&self) -> String {
+        format!("template:{}", self.template)
+    }
+}
+
+/// Create the launcher described by `spec`: `exe`, `binary:PATH` or `template:CMD`.
+pub fn from_spec(spec: &str) -> Result<Box<dyn Launcher>, String> {
+    if spec == "exe" {
+        CurrentExe::new().map(|l| Box::new(l) as Box<dyn Launcher>).map_err(|e| format!("cannot find current executable: {}", e))
+    } else if let Some(path) = spec.strip_prefix("binary:") {
+        Ok(Box::new(Binary::new(path)))
+    } else if let Some(template) = spec.strip_prefix("template:") {
+        CommandTemplate::new(template).map(|l| Box::new(l) as Box<dyn Launcher>)
+    } else {
+        Err(format!("unknown launcher {:?}, expected exe, binary:PATH or template:CMD", spec))
+    }
+}
+
+mod test {
+
+    #[test]
+    fn command_template_expansion() {
+        let spec = crate::launcher::ProcessSpec { processes: 2, workers: 1, process: 3, join: 1, new_processes: 4, hostfile: None, host: None };
+        let template = crate::launcher::CommandTemplate::new("ssh host-{p} ./benchmark --id={p} {args}").unwrap();
+        assert_eq!(
+            template.expand(&spec),
+            vec!["ssh", "host-3", "./benchmark", "--id=3", "-n", "2", "-w", "1", "-p", "3", "--join", "1", "--nn", "4"]);
+        let spec = crate::launcher::ProcessSpec { host: Some("node-b".to_string()), ..spec };
+        assert_eq!(crate::launcher::CommandTemplate::new("ssh {host} true").unwrap().expand(&spec), vec!["ssh", "node-b", "true"]);
+    }
+
+    #[test]
+    fn cluster_extends_hostfile() {
+        let path = std::env::temp_dir().join(format!("hostfile-test-{}", std::process::id()));
+        let hostfile = crate::launcher::Hostfile::local(&path, 2).unwrap();
+        let mut cluster = crate::launcher::Cluster::new(2, 1, Some(hostfile));
+
+        let first = cluster.next_process(0).unwrap();
+        let retry = cluster.next_process(0).unwrap();
+        assert_eq!((retry.process, retry.new_processes), (2, 3));
+        assert_eq!(first.hostfile.as_ref(), Some(&path));
+        assert_eq!(crate::launcher::Hostfile::read(&path).unwrap().addresses().len(), 3);
+
+        cluster.joined();
+        let spec = cluster.next_process(1).unwrap();
+        assert_eq!((spec.processes, spec.process, spec.join, spec.new_processes), (2, 3, 1, 4));
+        assert_eq!(crate::launcher::Hostfile::read(&path).unwrap().addresses().len(), 4);
+        assert_eq!(cluster.topology().host_of(2), Some("127.0.0.1"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cluster_remote_hosts() {
+        let path = std::env::temp_dir().join(format!("hostfile-remote-test-{}", std::process::id()));
+        std::fs::write(&path, "127.0.0.1:2101\nnode-b:3000\nnode-b:3002\n").unwrap();
+        let hostfile = crate::launcher::Hostfile::read(&path).unwrap();
+        let mut cluster = crate::launcher::Cluster::new(3, 1, Some(hostfile));
+
+        // a process joining through a worker on node-b runs on node-b
+        cluster.next_process(2).unwrap();
+        assert_eq!(crate::launcher::Hostfile::read(&path).unwrap().addresses()[3], "node-b:3003");
+        cluster.next_process(0).unwrap();
+        assert!(crate::launcher::Hostfile::read(&path).unwrap().addresses()[3].starts_with("127.0.0.1:"));
+        cluster.joined();
+        assert_eq!(cluster.topology().host_of(1), Some("node-b"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cluster_worker_ranges() {
+        let mut cluster = crate::launcher::Cluster::new(2, 2, None);
+        let spec = cluster.next_process(0).unwrap();
+        assert_eq!((spec.process, spec.workers), (2, 2));
+        assert_eq!(cluster.joined(), vec![4..6]);
+        let specs = cluster.prepare(&[0, 3]).unwrap();
+        assert_eq!(specs.iter().map(|s| (s.process, s.join, s.new_processes)).collect::<Vec<_>>(), vec![(3, 0, 4), (4, 3, 5)]);
+        assert_eq!(cluster.pending_workers(), 4);
+        assert_eq!(cluster.joined(), vec![6..8, 8..10]);
+        assert_eq!(cluster.peers(), 10);
+        assert_eq!(cluster.process_of(3), Some(1));
+        assert_eq!(cluster.process_of(5), Some(2));
+        assert_eq!(cluster.process_of(6), Some(3));
+        assert_eq!(cluster.process_of(9), Some(4));
+        assert_eq!(cluster.process_of(10), None);
+        assert_eq!(cluster.topology().process_of(9), Some(4));
+        assert_eq!(cluster.topology().host_of(9), Some("localhost"));
+        assert_eq!(cluster.pending_workers(), 0);
+    }
+}
@@ -1,13 +1,24 @@
+pub mod analysis;
 pub mod kafka;
+pub mod launcher;
+pub mod metrics;
+pub mod migration;
+pub mod placement;
+pub mod simulation;
+pub mod status;
+pub mod supervisor;
 
 use timely::dataflow::{Scope, Stream};
 use timely::ExchangeData;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use timely::dataflow::operators::generic::operator::Operator;
 use timely::dataflow::channels::pact::Exchange;
 use rand::{Rng, SeedableRng};
 use rand::seq::IteratorRandom;
 use rand::rngs::StdRng;
+use crate::migration::{Move, PlanPreview};
+use crate::placement::{Distance, Topology};
+use dynamic_scaling_mechanism::ControlInst;
 
 pub fn verify<S: Scope, T: ExchangeData + Ord + ::std::fmt::Debug>(correct: &Stream<S, T>, output: &Stream<S, T>) -> Stream<S, ()> {
     let mut in1_pending: HashMap<_, Vec<_>> = Default::default();
@@ -50,15 +61,13 @@ pub fn verify<S: Scope, T: ExchangeData + Ord + ::std::fmt::Debug>(correct: &Str
 pub struct LinesGenerator {
     distinct_words: Vec<String>,
     words_per_line: usize,
-    rng: rand::rngs::ThreadRng,
+    rng: StdRng,
 }
 
 impl LinesGenerator {
     pub fn new(distinct_words: usize, words_per_line: usize, word_length: usize) -> Self {
-        let mut rng = rand::thread_rng();
-        let distinct_words = (0..distinct_words).map(|_| {
-            (0..word_length).map(|_| rng.sample(rand::distributions::Alphanumeric)).collect::<String>()
-        }).collect();
+        let mut rng = StdRng::from_entropy();
+        let distinct_words = Self::vocabulary(&mut rng, distinct_words, word_length);
 
         LinesGenerator {
             distinct_words,
@@ -67,6 +76,24 @@ impl LinesGenerator {
         }
     }
 
+    /// A generator whose vocabulary is the same for every `seed`, so that generators of different
+    /// workers produce lines over the same key space, while `seed` determines the sequence of lines.
+    pub fn new_seeded(distinct_words: usize, words_per_line: usize, word_length: usize, seed: u64) -> Self {
+        let distinct_words = Self::vocabulary(&mut StdRng::seed_from_u64(0), distinct_words, word_length);
+
+        LinesGenerator {
+            distinct_words,
+            words_per_line,
+            rng: StdRng::seed_from_u64(seed + 1),
+        }
+    }
+
+    fn vocabulary(rng: &mut StdRng, distinct_words: usize, word_length: usize) -> Vec<String> {
+        (0..distinct_words).map(|_| {
+            (0..word_length).map(|_| rng.sample(rand::distributions::Alphanumeric)).collect::<String>()
+        }).collect()
+    }
+
     pub fn next(&mut self) -> String {
         (0..self.words_per_line)
             .map(|_| self.distinct_words.iter().choose(&mut self.rng).unwrap().clone())
@@ -104,46 +131,217 @@ impl WordGenerator {
     }
 }
 
+/// Parse capacity weights given as `WORKER:WEIGHT,...`, see `LoadBalancer::set_capacity`.
+pub fn parse_capacities(spec: &str) -> Result<Vec<(usize, f64)>, String> {
+    spec.split(',').filter(|entry| !entry.trim().is_empty()).map(|entry| {
+        let mut parts = entry.trim().splitn(2, ':');
+        match (parts.next().map(|w| w.parse::<usize>()), parts.next().map(|c| c.parse::<f64>())) {
+            (Some(Ok(worker)), Some(Ok(capacity))) if capacity >= 0. && capacity.is_finite() => Ok((worker, capacity)),
+            _ => Err(format!("invalid capacity {:?}, expected WORKER:WEIGHT", entry)),
+        }
+    }).collect()
+}
+
+/// Bins per unit of capacity, infinite for workers without capacity that still have bins.
+fn load(bins: usize, capacity: f64) -> f64 {
+    if capacity > 0. { bins as f64 / capacity } else if bins > 0 { f64::INFINITY } else { 0. }
+}
+
+/// Balances bins over workers in proportion to their capacity (1 unless set with `set_capacity`).
+/// Workers are ordered by index and ties are broken towards the lowest index, so the same
+/// assignment always leads to the same moves. A `Topology` (see `set_topology`) does not change how
+/// many bins move, but which ones and between which workers: among equally loaded workers it moves
+/// bins between the closest ones, and it keeps neighbouring bins in the same process. The records of
+/// every bin (see `set_bin_loads`) likewise choose the bins that balance the records best.
+#[derive(Clone)]
 pub struct LoadBalancer {
     bins: usize,
-    worker2bins: HashMap<usize, VecDeque<usize>>,
+    worker2bins: BTreeMap<usize, VecDeque<usize>>,
+    capacities: BTreeMap<usize, f64>,
+    topology: Option<Topology>,
+    bin_loads: Option<Vec<u64>>,
 }
 
 impl LoadBalancer {
     pub fn new(workers: Vec<usize>, bins: usize) -> Self {
         // initialize the mapping as inside the stateful operators
         let map: Vec<usize> = (0..workers.len()).cycle().take(bins).collect();
-        let worker2bins = workers.iter().map(|worker| {
-            (*worker, map.iter().enumerate().filter_map(|(i, w)| if w == worker { Some(i) } else { None }).collect())
-        }).collect();
+        Self::from_map(workers, map)
+    }
 
-        LoadBalancer { bins, worker2bins }
+    /// The assignment `map` (the worker of every bin), e.g. after a `ControlInst::Map`. `workers`
+    /// without bins in `map` are kept without bins, workers of `map` missing from `workers` are added.
+    pub fn from_map(workers: Vec<usize>, map: Vec<usize>) -> Self {
+        let mut worker2bins: BTreeMap<usize, VecDeque<usize>> = workers.into_iter().map(|worker| (worker, VecDeque::new())).collect();
+        for (bin, worker) in map.iter().enumerate() {
+            worker2bins.entry(*worker).or_default().push_back(bin);
+        }
+
+        LoadBalancer { bins: map.len(), worker2bins, capacities: BTreeMap::new(), topology: None, bin_loads: None }
     }
+
     pub fn add_workers(&mut self, new_workers: Vec<usize>) -> impl Iterator<Item=(usize, usize)> {
         // initial empty assignments
         self.worker2bins.extend(new_workers.iter().map(|w| (*w, VecDeque::new())));
+        self.rebalance()
+    }
+
+    /// The capacity weight of `worker`.
+    pub fn capacity(&self, worker: usize) -> f64 {
+        self.capacities.get(&worker).cloned().unwrap_or(1.)
+    }
+
+    /// Set the capacity weight of `worker`, which can also be a worker that did not join yet.
+    /// A worker with capacity 0 gets no bins, so that lowering the capacity step by step drains
+    /// it gradually. `rebalance` returns the moves the new capacity calls for.
+    pub fn set_capacity(&mut self, worker: usize, capacity: f64) {
+        assert!(capacity >= 0. && capacity.is_finite(), "invalid capacity {} of worker {}", capacity, worker);
+        self.capacities.insert(worker, capacity);
+    }
 
+    /// Place bins knowing the process and host of every worker, see `rebalance`.
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = Some(topology);
+    }
+
+    /// Place bins knowing the records of every bin, e.g. the ones observed so far, see `bin_to_move`.
+    pub fn set_bin_loads(&mut self, records: Vec<u64>) {
+        assert_eq!(records.len(), self.bins, "one load per bin");
+        self.bin_loads = Some(records);
+    }
+
+    /// Move bins until no move lowers the load of the most loaded worker: the most loaded worker gives
+    /// a bin to the worker that is least loaded after receiving it. Without a topology it gives its last
+    /// bin, and ties between workers go to the lowest indices. With a topology, ties go to the pair of
+    /// workers closest to each other (same process, then same host). With a topology or bin loads, the bin
+    /// is chosen by `bin_to_move`. Either way the number of bins of every worker is the same.
+    pub fn rebalance(&mut self) -> impl Iterator<Item=Move> {
         let mut moves = Vec::new();
 
         loop {
-            let max = self.worker2bins.iter().max_by_key(|(_w, bins)| bins.len()).unwrap();
-            let min = self.worker2bins.iter().min_by_key(|(_w, bins)| bins.len()).unwrap();
+            let loads = self.worker2bins.iter()
+                .map(|(&worker, bins)| (worker, load(bins.len(), self.capacity(worker)), load(bins.len() + 1, self.capacity(worker))))
+                .collect::<Vec<_>>();
+            let max = loads.iter().map(|&(_, load, _)| load).fold(0., f64::max);
+            let min = loads.iter().filter(|&&(worker, _, _)| self.capacity(worker) > 0.).map(|&(_, _, after)| after).fold(f64::INFINITY, f64::min);
+            if min >= max { break } // we are done, work is balanced
 
-            let delta =  max.1.len() - min.1.len();
-            if delta <= 1 { break } // we are done, work is balanced
+            let loaded = loads.iter().filter(|&&(_, load, _)| load == max).map(|&(worker, _, _)| worker);
+            let not_loaded = loads.iter().filter(|&&(worker, _, after)| after == min && self.capacity(worker) > 0.).map(|&(worker, _, _)| worker).collect::<Vec<_>>();
+            let (loaded_w, not_loaded_w) = loaded
+                .flat_map(|from| not_loaded.iter().map(move |&to| (from, to)))
+                .min_by_key(|&(from, to)| (self.topology.as_ref().map(|t| t.distance(from, to)), from, to))
+                .unwrap();
 
-            let loaded_w = *max.0;
-            let not_loaded_w = *min.0;
-            let bin_to_move = self.worker2bins.get_mut(&loaded_w).unwrap().pop_back().unwrap();
+            let index = self.bin_to_move(loaded_w, not_loaded_w);
+            let bin_to_move = self.worker2bins.get_mut(&loaded_w).unwrap().remove(index).unwrap();
             self.worker2bins.get_mut(&not_loaded_w).unwrap().push_back(bin_to_move);
             moves.push((bin_to_move, not_loaded_w));
         }
         moves.into_iter()
     }
 
+    /// Index among the bins of `from` of the bin to give to `to`, by default the last bin.
+    /// * With bin loads, it is the bin whose records are closest to what both workers lack to reach
+    ///   their share of the records (in proportion to their capacity) with their share of the bins.
+    /// * With a topology, and `to` in another process, it is the bin with the most neighbours (bins
+    ///   `bin - 1` and `bin + 1`, the adjacent ranges of the key hash) already in the process of `to`.
+    ///   A process then receives contiguous ranges of bins rather than bins scattered over the key space.
+    ///
+    /// Ties go to the last bin.
+    fn bin_to_move(&self, from: usize, to: usize) -> usize {
+        let bins = &self.worker2bins[&from];
+        if let Some(records) = self.bin_loads.as_ref() {
+            // the share of the records and of the bins of a worker, in proportion to its capacity
+            let capacity = self.worker2bins.keys().map(|&worker| self.capacity(worker)).sum::<f64>();
+            let total = records.iter().sum::<u64>() as f64;
+            let share = |worker: usize| self.capacity(worker) / capacity;
+            let load = |worker: usize| self.worker2bins[&worker].iter().map(|&bin| records[bin]).sum::<u64>() as f64;
+            let count = |worker: usize| self.worker2bins[&worker].len() as f64;
+            // records per bin `from` has to give away and `to` has to receive to reach their share
+            let give = (load(from) - total * share(from)) / f64::max(1., count(from) - self.bins as f64 * share(from));
+            let take = (total * share(to) - load(to)) / f64::max(1., self.bins as f64 * share(to) - count(to));
+            let distance = |bin: usize| (records[bin] as f64 - (give + take) / 2.).abs();
+            // `min_by` returns the first minimum, the last bin when iterating in reverse
+            return (0..bins.len()).rev().min_by(|&a, &b| distance(bins[a]).partial_cmp(&distance(bins[b])).unwrap()).unwrap();
+        }
+        let topology = match self.topology.as_ref() {
+            Some(topology) if topology.distance(from, to) != Distance::IntraProcess => topology,
+            _ => return bins.len() - 1,
+        };
+        let assignment = self.assignment();
+        let neighbours = |bin: usize| {
+            [bin.checked_sub(1), Some(bin + 1)].iter()
+                .filter_map(|&neighbour| neighbour.filter(|&n| n < self.bins))
+                .filter(|&n| topology.distance(assignment[n], to) == Distance::IntraProcess)
+                .count()
+        };
+        // `max_by_key` returns the last maximum
+        (0..bins.len()).max_by_key(|&index| neighbours(bins[index])).unwrap()
+    }
+
+    /// The worker of every bin.
+    pub fn assignment(&self) -> Vec<usize> {
+        let mut assignment = vec![0; self.bins];
+        for (&worker, bins) in self.worker2bins.iter() {
+            for &bin in bins.iter() {
+                assignment[bin] = worker;
+            }
+        }
+        assignment
+    }
+
+    /// The `ControlInst::Map` installing the current assignment.
+    pub fn map_control(&self) -> ControlInst {
+        ControlInst::Map(self.assignment())
+    }
+
+    /// Dry run of `add_workers`: what adding `new_workers` would do, without changing the assignment.
+    /// `bin_bytes` estimates the state size of a bin.
+    pub fn preview<F: Fn(usize) -> u64>(&self, new_workers: Vec<usize>, bin_bytes: F) -> PlanPreview {
+        let mut proposed = self.clone();
+        proposed.add_workers(new_workers).for_each(drop);
+        PlanPreview {
+            current: self.assignment(),
+            proposed: proposed.assignment(),
+            bin_bytes: (0..self.bins).map(bin_bytes).collect(),
+        }
+    }
+
+    /// The worker `bin` is assigned to.
+    pub fn worker_of(&self, bin: usize) -> Option<usize> {
+        self.worker2bins.iter().find(|(_, bins)| bins.contains(&bin)).map(|(worker, _)| *worker)
+    }
+
+    /// Go back to the assignment of `snapshot`, taken before a plan was applied. Workers added
+    /// since the snapshot are kept, without bins.
+    pub fn restore(&mut self, snapshot: LoadBalancer) {
+        let workers = self.worker2bins.keys().cloned().collect::<Vec<_>>();
+        self.worker2bins = snapshot.worker2bins;
+        for worker in workers {
+            self.worker2bins.entry(worker).or_default();
+        }
+    }
+
+    /// Adopt the observed worker of every bin, `None` for bins that were not observed (see `status::Status`).
+    /// Returns the bins the assignment disagreed on, as `(bin, assigned worker, observed worker)`.
+    pub fn reconcile(&mut self, owners: &[Option<usize>]) -> Vec<(usize, usize, usize)> {
+        let assignment = self.assignment();
+        let mut differences = Vec::new();
+        for (bin, owner) in owners.iter().enumerate() {
+            if let Some(owner) = *owner {
+                if assignment[bin] != owner {
+                    self.worker2bins.get_mut(&assignment[bin]).unwrap().retain(|b| *b != bin);
+                    self.worker2bins.entry(owner).or_default().push_back(bin);
+                    differences.push((bin, assignment[bin], owner));
+                }
+            }
+        }
+        differences
+    }
+
     pub fn dump_map(&self) {
-        let mut map = self.worker2bins.iter().collect::<Vec<_>>();
-        map.sort();
+        let map = self.worker2bins.iter().collect::<Vec<_>>();
         println!("map: {:?}", map);
     }
 
@@ -151,9 +349,7 @@ impl LoadBalancer {
     fn verify(&self) {
         // self.dump_map();
         // properly balanced
-        let max = self.worker2bins.values().map(|bins| bins.len()).max().unwrap();
-        let min = self.worker2bins.values().map(|bins| bins.len()).min().unwrap();
-        assert!(max - min <= 1);
+        assert_eq!(self.clone().rebalance().count(), 0);
 
         // every bin is assigned
         let assigned_bins: usize = self.worker2bins.values().map(|bins| bins.len()).sum();
@@ -174,4 +370,148 @@ mod test {
 
         lb.dump_map();
     }
+
+    #[test]
+    fn load_balancer_deterministic() {
+        let plan = || {
+            let mut lb = crate::LoadBalancer::new(vec![0, 1], 8);
+            lb.add_workers(vec![2]).collect::<Vec<_>>()
+        };
+        // workers 0 and 1 are tied, the lowest index gives its bin first
+        assert_eq!(plan(), vec![(6, 2), (7, 2)]);
+        assert_eq!(plan(), plan());
+
+        let mut lb = crate::LoadBalancer::new((0..3).collect(), 12);
+        assert_eq!(lb.add_workers(vec![3, 4]).collect::<Vec<_>>(), vec![(9, 3), (10, 4), (11, 3), (6, 4)]);
+        assert_eq!(lb.assignment(), vec![0, 1, 2, 0, 1, 2, 4, 1, 2, 3, 4, 3]);
+    }
+
+    #[test]
+    fn load_balancer_capacities() {
+        let mut lb = crate::LoadBalancer::new(vec![0, 1], 12);
+        lb.set_capacity(1, 2.);
+        assert_eq!(lb.rebalance().collect::<Vec<_>>(), vec![(10, 1), (8, 1)]);
+        let bins_of = |lb: &crate::LoadBalancer, worker| lb.assignment().iter().filter(|w| **w == worker).count();
+        assert_eq!((bins_of(&lb, 0), bins_of(&lb, 1)), (4, 8));
+
+        // drain worker 0 gradually
+        lb.set_capacity(0, 0.5);
+        assert_eq!(lb.rebalance().count(), 2);
+        assert_eq!((bins_of(&lb, 0), bins_of(&lb, 1)), (2, 10));
+        lb.set_capacity(0, 0.);
+        assert_eq!(lb.rebalance().collect::<Vec<_>>(), vec![(2, 1), (0, 1)]);
+        assert_eq!(lb.assignment(), vec![1; 12]);
+
+        // a new worker with a capacity set before it joins
+        lb.set_capacity(2, 3.);
+        lb.add_workers(vec![2]).for_each(drop);
+        assert_eq!((bins_of(&lb, 1), bins_of(&lb, 2)), (5, 7));
+
+        assert_eq!(crate::parse_capacities("0:2, 3:0.5"), Ok(vec![(0, 2.), (3, 0.5)]));
+        assert!(crate::parse_capacities("0:-1").is_err());
+        assert!(crate::parse_capacities("0").is_err());
+    }
+
+    #[test]
+    fn load_balancer_topology() {
+        use crate::placement::Topology;
+
+        // workers 0 and 1 share a process, 2 and 3 the other one
+        let map = vec![1, 2, 1, 2, 2, 3, 1, 1, 3, 2, 0];
+        let mut lb = crate::LoadBalancer::from_map((0..4).collect(), map.clone());
+        assert_eq!(lb.rebalance().collect::<Vec<_>>(), vec![(7, 0), (9, 0)]);
+        let mut lb = crate::LoadBalancer::from_map((0..4).collect(), map);
+        lb.set_topology(Topology::uniform(2, 2));
+        let preview = lb.preview(vec![], |_| 10);
+        // equally balanced, without moving bin 9 across processes
+        assert_eq!(lb.rebalance().collect::<Vec<_>>(), vec![(7, 0), (9, 3)]);
+        let traffic = preview.traffic(&Topology::uniform(2, 2));
+        assert_eq!((traffic.intra_process.bins, traffic.cross_process.bins, traffic.cross_process.bytes), (2, 0, 0));
+
+        // scaling out from one process to two: as many bins move, but the new process gets the
+        // contiguous bins 2..6 instead of 2, 3, 6 and 7
+        let map = vec![0, 0, 0, 0, 1, 1, 1, 1];
+        let mut flat = crate::LoadBalancer::from_map(vec![0, 1], map.clone());
+        assert_eq!(flat.add_workers(vec![2, 3]).collect::<Vec<_>>(), vec![(3, 2), (7, 3), (2, 2), (6, 3)]);
+        let mut lb = crate::LoadBalancer::from_map(vec![0, 1], map);
+        lb.set_topology(Topology::uniform(2, 2));
+        assert_eq!(lb.add_workers(vec![2, 3]).collect::<Vec<_>>(), vec![(3, 2), (4, 3), (2, 2), (5, 3)]);
+        assert_eq!(lb.assignment(), vec![0, 0, 2, 2, 3, 3, 1, 1]);
+
+        // scaling out onto the host of the second process takes the bins from that process
+        let hosts = Topology::new(vec![0..2, 2..4, 4..6], vec!["a".to_string(), "b".to_string(), "b".to_string()]);
+        let flat = crate::LoadBalancer::new((0..4).collect(), 8).preview(vec![4, 5], |_| 10);
+        assert_eq!(flat.moves(), vec![(4, 4), (5, 5)]);
+        assert_eq!(flat.traffic(&hosts).cross_host.bins, 2);
+        let mut lb = crate::LoadBalancer::new((0..4).collect(), 8);
+        lb.set_topology(hosts.clone());
+        let preview = lb.preview(vec![4, 5], |_| 10);
+        assert_eq!(preview.moves(), vec![(6, 4), (7, 5)]);
+        let traffic = preview.traffic(&hosts);
+        assert_eq!((traffic.cross_process.bins, traffic.cross_host.bins), (2, 0));
+    }
+
+    #[test]
+    fn load_balancer_bin_loads() {
+        // bins 0 and 2 are twice as loaded as the others
+        let records = vec![20, 10, 20, 10, 10, 10, 10, 10];
+        let mut lb = crate::LoadBalancer::new(vec![0, 1], 8);
+        lb.set_bin_loads(records.clone());
+        assert_eq!(lb.add_workers(vec![2, 3]).collect::<Vec<_>>(), vec![(6, 2), (7, 3), (2, 2), (5, 3)]);
+        let loads = (0..4).map(|worker| lb.assignment().iter().zip(records.iter()).filter(|(w, _)| **w == worker).map(|(_, r)| r).sum::<u64>()).collect::<Vec<_>>();
+        assert_eq!(loads, vec![30, 20, 30, 20]);
+    }
+
+    #[test]
+    fn load_balancer_restore() {
+        let mut lb = crate::LoadBalancer::new(vec![0, 1], 8);
+        let snapshot = lb.clone();
+        let moves = lb.add_workers(vec![2]).collect::<Vec<_>>();
+        assert!(moves.iter().all(|&(bin, to)| to == 2 && lb.worker_of(bin) == Some(2)));
+
+        lb.restore(snapshot);
+        assert!(moves.iter().all(|&(bin, _)| lb.worker_of(bin) == Some(bin % 2)));
+        // the new worker is kept, without bins
+        assert_eq!(lb.add_workers(vec![]).count(), moves.len());
+    }
+
+    #[test]
+    fn load_balancer_preview() {
+        let lb = crate::LoadBalancer::new(vec![0, 1], 8);
+        let preview = lb.preview(vec![2, 3], |_| 100);
+        assert_eq!(preview.current, lb.assignment());
+        assert_eq!(preview.moves().len(), 4);
+        assert_eq!(preview.moved_bytes(), 400);
+        assert_eq!(preview.imbalance().1, 0.);
+        // a dry run does not change the assignment
+        assert_eq!(lb.assignment(), vec![0, 1, 0, 1, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn load_balancer_from_map() {
+        use dynamic_scaling_mechanism::ControlInst;
+
+        let mut lb = crate::LoadBalancer::from_map(vec![0, 1, 2, 3], vec![2; 8]);
+        assert_eq!(lb.assignment(), vec![2; 8]);
+        assert_eq!(lb.worker_of(5), Some(2));
+        match lb.map_control() {
+            ControlInst::Map(map) => assert_eq!(map, vec![2; 8]),
+            _ => panic!("expected a Map instruction"),
+        }
+        // the workers without bins get their share
+        assert_eq!(lb.add_workers(vec![]).count(), 6);
+
+        // the initial layout of the stateful operators
+        let lb = crate::LoadBalancer::from_map(vec![], vec![0, 1, 0, 1]);
+        assert_eq!(lb.assignment(), crate::LoadBalancer::new(vec![0, 1], 4).assignment());
+    }
+
+    #[test]
+    fn load_balancer_reconcile() {
+        let mut lb = crate::LoadBalancer::new(vec![0, 1], 4);
+        let differences = lb.reconcile(&[Some(0), Some(0), None, Some(2)]);
+        assert_eq!(differences, vec![(1, 1, 0), (3, 1, 2)]);
+        assert_eq!(lb.assignment(), vec![0, 0, 0, 2]);
+        assert!(lb.reconcile(&[Some(0), None, None, Some(2)]).is_empty());
+    }
 }
@@ -0,0 +1,307 @@
+//! Self-describing metrics output for the benchmarks.
+//!
+//! A metrics file starts with a metadata header, one `# key<TAB>value` line per entry,
+//! followed by tab-separated records whose first field names the record kind
+//! (e.g. `summary_timeline` or `spawn_metric`). Readers should skip record kinds
+//! they do not know about, so that new kinds can be added without breaking old tools.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process::Command;
+
+/// Name of the format, written as the first metadata entry.
+pub const FORMAT_NAME: &str = "rescaling-examples-metrics";
+/// Bumped whenever the meaning of an existing record kind changes.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Ordered list of `key -> value` pairs describing a run.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        let mut metadata = Metadata::default();
+        metadata.set("format", FORMAT_NAME);
+        metadata.set("version", FORMAT_VERSION);
+        metadata
+    }
+
+    /// Set `key` to `value`, replacing a previous value for the same key.
+    pub fn set<V: Display>(&mut self, key: &str, value: V) {
+        let value = value.to_string().replace(&['\t', '\n'][..], " ");
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&(String, String)> {
+        self.entries.iter()
+    }
+
+    /// Record information about the environment the run is executed in:
+    /// git revision, host name, OS, architecture and start time.
+    pub fn collect_environment(&mut self) {
+        let revision = command_output("git", &["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+        let dirty = command_output("git", &["status", "--porcelain", "--untracked-files=no"]).map(|s| !s.is_empty());
+        self.set("git_revision", revision);
+        self.set("git_dirty", dirty.map(|d| d.to_string()).unwrap_or_else(|| "unknown".to_string()));
+        self.set("host", hostname());
+        self.set("os", std::env::consts::OS);
+        self.set("arch", std::env::consts::ARCH);
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.set("started_unix_s", started);
+    }
+}
+
+fn command_output(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok().map(|s| s.trim().to_string())
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .ok()
+        .or_else(|| command_output("hostname", &[]))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Writes a metrics file: the metadata header first, then the records.
+pub struct MetricsWriter {
+    out: BufWriter<File>,
+}
+
+impl MetricsWriter {
+    pub fn create<P: AsRef<Path>>(path: P, metadata: &Metadata) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for (key, value) in metadata.iter() {
+            writeln!(out, "# {}\t{}", key, value)?;
+        }
+        Ok(MetricsWriter { out })
+    }
+
+    /// Write a single record of the given `kind`.
+    pub fn record<I, V>(&mut self, kind: &str, fields: I) -> io::Result<()>
+    where I: IntoIterator<Item=V>, V: Display {
+        write!(self.out, "{}", kind)?;
+        for field in fields {
+            write!(self.out, "\t{}", field)?;
+        }
+        writeln!(self.out)
+    }
+
+    /// Write lines that are already formatted as records (e.g. `format_summary_timeline`).
+    pub fn raw(&mut self, lines: &str) -> io::Result<()> {
+        for line in lines.lines().filter(|l| !l.trim().is_empty()) {
+            writeln!(self.out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// A parsed metrics file.
+#[derive(Debug, Default)]
+pub struct MetricsFile {
+    pub metadata: Metadata,
+    /// Records in file order, as `(kind, fields)`.
+    pub records: Vec<(String, Vec<String>)>,
+}
+
+impl MetricsFile {
+    /// Read a metrics file. Files without a header (written before the format was versioned)
+    /// are accepted and reported with an empty metadata.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut file = MetricsFile::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('#') {
+                let mut tokens = header.trim_start().splitn(2, '\t');
+                let key = tokens.next().unwrap_or("").trim();
+                if !key.is_empty() {
+                    file.metadata.set(key, tokens.next().unwrap_or("").trim());
+                }
+                continue;
+            }
+            let mut tokens = line.split('\t').map(|t| t.trim().to_string());
+            let kind = tokens.next().unwrap();
+            file.records.push((kind, tokens.collect()));
+        }
+        if let Some(version) = file.metadata.get("version") {
+            if version.parse::<u32>().map(|v| v > FORMAT_VERSION).unwrap_or(true) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported metrics version {}", version)));
+            }
+        }
+        Ok(file)
+    }
+
+    /// Iterate over the fields of all records of the given `kind`.
+    pub fn records<'a>(&'a self, kind: &'a str) -> impl Iterator<Item=&'a [String]> + 'a {
+        self.records.iter().filter(move |(k, _)| k == kind).map(|(_, fields)| &fields[..])
+    }
+}
+
+/// Aggregate of the records processed by one worker in one timeline bucket.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WorkerBucket {
+    pub records: u64,
+    pub latency_sum: u128,
+    pub latency_max: u64,
+}
+
+impl WorkerBucket {
+    pub fn mean_latency(&self) -> u64 {
+        if self.records == 0 { 0 } else { (self.latency_sum / self.records as u128) as u64 }
+    }
+}
+
+/// Per-worker processed-record and latency timelines, bucketed by record timestamp.
+#[derive(Debug, Clone)]
+pub struct WorkerTimelines {
+    bucket_ns: u64,
+    buckets: std::collections::BTreeMap<(usize, u64), WorkerBucket>,
+}
+
+impl WorkerTimelines {
+    pub fn new(bucket_ns: u64) -> Self {
+        WorkerTimelines { bucket_ns, buckets: Default::default() }
+    }
+
+    /// `worker` processed `records` records with timestamp `time`, observed with the given `latency`.
+    pub fn add(&mut self, worker: usize, time: u64, latency: u64, records: u64) {
+        let bucket = self.buckets.entry((worker, time / self.bucket_ns * self.bucket_ns)).or_default();
+        bucket.records += records;
+        bucket.latency_sum += latency as u128 * records as u128;
+        bucket.latency_max = bucket.latency_max.max(latency);
+    }
+
+    /// Buckets ordered by worker and time, as `(worker, bucket_start, bucket)`.
+    pub fn iter(&self) -> impl Iterator<Item=(usize, u64, &WorkerBucket)> {
+        self.buckets.iter().map(|(&(worker, time), bucket)| (worker, time, bucket))
+    }
+
+    /// Aggregate of the buckets of every worker starting at or after `since`.
+    pub fn since(&self, since: u64) -> HashMap<usize, WorkerBucket> {
+        let mut aggregate: HashMap<usize, WorkerBucket> = HashMap::new();
+        for (worker, _, bucket) in self.iter().filter(|&(_, time, _)| time >= since) {
+            let total = aggregate.entry(worker).or_default();
+            total.records += bucket.records;
+            total.latency_sum += bucket.latency_sum;
+            total.latency_max = total.latency_max.max(bucket.latency_max);
+        }
+        aggregate
+    }
+
+    /// Write one `worker_timeline` record (worker, time, records, mean and max latency) per bucket.
+    pub fn write(&self, metrics: &mut MetricsWriter) -> io::Result<()> {
+        for (worker, time, bucket) in self.iter() {
+            metrics.record("worker_timeline", [worker as u64, time, bucket.records, bucket.mean_latency(), bucket.latency_max])?;
+        }
+        Ok(())
+    }
+}
+
+/// Net accumulation of `(key, delta)` updates, keeping only the keys with a non-zero count.
+/// Used to track the size of progress-tracking state, where most updates cancel out.
+#[derive(Debug, Clone)]
+pub struct Accumulation<K: Eq + Hash> {
+    counts: HashMap<K, i64>,
+    updates: u64,
+}
+
+impl<K: Eq + Hash> Default for Accumulation<K> {
+    fn default() -> Self {
+        Accumulation { counts: Default::default(), updates: 0 }
+    }
+}
+
+impl<K: Eq + Hash> Accumulation<K> {
+    pub fn update(&mut self, key: K, delta: i64) {
+        self.updates += 1;
+        match self.counts.entry(key) {
+            Entry::Occupied(mut entry) => {
+                *entry.get_mut() += delta;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            },
+            Entry::Vacant(entry) => {
+                if delta != 0 {
+                    entry.insert(delta);
+                }
+            },
+        }
+    }
+
+    /// Number of keys with a non-zero count.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Total number of updates applied so far.
+    pub fn updates(&self) -> u64 {
+        self.updates
+    }
+}
+
+mod test {
+
+    #[test]
+    fn metrics_round_trip() {
+        use crate::metrics::{Metadata, MetricsFile, MetricsWriter, FORMAT_VERSION};
+
+        let path = std::env::temp_dir().join(format!("metrics-round-trip-{}", std::process::id()));
+        let mut metadata = Metadata::new();
+        metadata.set("rate", 100);
+        metadata.set("note", "tabs\tand\nnewlines");
+        let mut writer = MetricsWriter::create(&path, &metadata).unwrap();
+        writer.record("throughput", [0u64, 10]).unwrap();
+        writer.record("spawn_metric", [5u64, 7]).unwrap();
+        writer.raw("throughput\t1000\t20\n").unwrap();
+        writer.finish().unwrap();
+
+        let file = MetricsFile::read(&path).unwrap();
+        assert_eq!(file.metadata.get("version"), Some(FORMAT_VERSION.to_string().as_str()));
+        assert_eq!(file.metadata.get("rate"), Some("100"));
+        assert_eq!(file.metadata.get("note"), Some("tabs and newlines"));
+        let throughput = file.records("throughput").map(|fields| fields.to_vec()).collect::<Vec<_>>();
+        assert_eq!(throughput, vec![vec!["0".to_string(), "10".to_string()], vec!["1000".to_string(), "20".to_string()]]);
+        assert_eq!(file.records("spawn_metric").count(), 1);
+
+        // files of a newer or unknown version are rejected, files without a header are accepted
+        for (header, ok) in [(format!("# version\t{}\n", FORMAT_VERSION + 1), false), ("# version\tx\n".to_string(), false), (String::new(), true)].iter() {
+            std::fs::write(&path, format!("{}throughput\t0\t1\n", header)).unwrap();
+            assert_eq!(MetricsFile::read(&path).is_ok(), *ok, "header {:?}", header);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
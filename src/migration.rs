@@ -0,0 +1,416 @@
+//! Issuing a migration plan in waves.
+//!
+//! Migrating all the bins of a plan under one configuration moves all their state at once,
+//! which shows up as a latency spike. The `Strategy` decides how a plan is cut into configurations
+//! (all at once, fluid with one bin per configuration, or batches of `k` bins), and a
+//! `MigrationSchedule` further splits it into waves of at most `max_bins` bins and `max_bytes`
+//! bytes of state. A wave is released immediately, once the previous one is acknowledged or,
+//! with `Pacing::Interval`, once the pacing interval elapsed. Each wave gets its own
+//! configuration at its own timestamp.
+//!
+//! A plan is committed once its last wave is acknowledged. If that does not happen by a deadline,
+//! `MigrationSchedule::rollback` returns the compensating moves bringing the issued bins back.
+//!
+//! A `PlanPreview` describes what a plan would do without issuing it.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::placement::{Topology, Traffic};
+
+/// A bin move, as `(bin, target worker)`.
+pub type Move = (usize, usize);
+
+/// How a migration plan is cut into configurations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// A single configuration with every move
+    AllAtOnce,
+    /// One configuration per bin, at successive timestamps
+    Fluid,
+    /// Configurations of `k` bins, at successive timestamps
+    Batched(usize),
+}
+
+impl Strategy {
+    /// The wave limits enforcing the strategy on top of `limits`.
+    pub fn limits(&self, limits: WaveLimits) -> WaveLimits {
+        let max_bins = match *self {
+            Strategy::AllAtOnce => limits.max_bins,
+            Strategy::Fluid => Some(1),
+            Strategy::Batched(k) => Some(limits.max_bins.map(|max| max.min(k)).unwrap_or(k)),
+        };
+        WaveLimits { max_bins, ..limits }
+    }
+
+    /// Pacing used when none is configured: the configurations of a fluid or batched migration are
+    /// issued together and applied by Megaphone one after the other.
+    pub fn default_pacing(&self) -> Pacing {
+        match self {
+            Strategy::AllAtOnce => Pacing::Acknowledged,
+            Strategy::Fluid | Strategy::Batched(_) => Pacing::Immediate,
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all-at-once" => Ok(Strategy::AllAtOnce),
+            "fluid" => Ok(Strategy::Fluid),
+            _ => match s.strip_prefix("batched:").map(|k| k.parse()) {
+                Some(Ok(k)) if k > 0 => Ok(Strategy::Batched(k)),
+                _ => Err(format!("unknown migration strategy {:?}, expected all-at-once, fluid or batched:K", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::AllAtOnce => write!(f, "all-at-once"),
+            Strategy::Fluid => write!(f, "fluid"),
+            Strategy::Batched(k) => write!(f, "batched:{}", k),
+        }
+    }
+}
+
+/// When the next wave of a migration is issued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pacing {
+    /// Right after the previous wave, at the next timestamp
+    Immediate,
+    /// Once the previous wave is acknowledged, i.e. its configuration was applied
+    Acknowledged,
+    /// A fixed time (ns) after the previous wave
+    Interval(u64),
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(Pacing::Immediate),
+            "ack" => Ok(Pacing::Acknowledged),
+            _ => s.strip_suffix("ms")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .map(|ms| Pacing::Interval(ms * 1_000_000))
+                .ok_or_else(|| format!("unknown pacing {:?}, expected immediate, ack or an interval such as 500ms", s)),
+        }
+    }
+}
+
+impl fmt::Display for Pacing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pacing::Immediate => write!(f, "immediate"),
+            Pacing::Acknowledged => write!(f, "ack"),
+            Pacing::Interval(ns) => write!(f, "{}ms", ns / 1_000_000),
+        }
+    }
+}
+
+/// Limits of a single wave, `None` is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaveLimits {
+    pub max_bins: Option<usize>,
+    pub max_bytes: Option<u64>,
+}
+
+/// Split `moves` into waves of at most `limits.max_bins` bins and `limits.max_bytes` bytes, where
+/// `bin_bytes` is the (estimated) state size of a bin. A bin larger than `max_bytes` gets a wave of its own.
+pub fn split_waves<F: Fn(usize) -> u64>(moves: Vec<Move>, limits: WaveLimits, bin_bytes: F) -> Vec<Vec<Move>> {
+    let mut waves = Vec::new();
+    let mut wave = Vec::new();
+    let mut wave_bytes = 0;
+    for mv in moves {
+        let bytes = bin_bytes(mv.0);
+        let full_bins = limits.max_bins.map(|max| wave.len() >= max).unwrap_or(false);
+        let full_bytes = limits.max_bytes.map(|max| wave_bytes + bytes > max).unwrap_or(false);
+        if !wave.is_empty() && (full_bins || full_bytes) {
+            waves.push(std::mem::take(&mut wave));
+            wave_bytes = 0;
+        }
+        wave.push(mv);
+        wave_bytes += bytes;
+    }
+    if !wave.is_empty() {
+        waves.push(wave);
+    }
+    waves
+}
+
+/// Estimated state size of a word count entry: the word and its heap allocation, the count and the hash map overhead.
+pub const STATE_ENTRY_BYTES: u64 = 48;
+
+/// Estimated state size of a bin when `key_space` keys spread evenly over `bins` bins.
+pub fn bin_bytes(key_space: u64, bins: usize) -> u64 {
+    (key_space / bins as u64).max(1) * STATE_ENTRY_BYTES
+}
+
+/// Estimated state size of every bin: `state_bytes` split in proportion to the records observed
+/// in every bin, or evenly while no record was observed.
+pub fn bin_sizes(state_bytes: u64, records: &[u64]) -> Vec<u64> {
+    let total = records.iter().sum::<u64>();
+    if total == 0 {
+        let bins = records.len().max(1) as u64;
+        return vec![state_bytes / bins; records.len()];
+    }
+    records.iter().map(|&r| (state_bytes as u128 * r as u128 / total as u128) as u64).collect()
+}
+
+/// The moves turning the assignment `current` into `target` (the worker of every bin): one move
+/// per bin whose worker differs, none of which can be avoided.
+pub fn plan_moves(current: &[usize], target: &[usize]) -> Vec<Move> {
+    assert_eq!(current.len(), target.len(), "assignments of different numbers of bins");
+    current.iter().zip(target.iter()).enumerate()
+        .filter(|(_, (current, target))| current != target)
+        .map(|(bin, (_, &target))| (bin, target))
+        .collect()
+}
+
+/// Dry run of a plan: the bin→worker map before and after it, with the moved bins and state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanPreview {
+    /// Worker of every bin before the plan
+    pub current: Vec<usize>,
+    /// Worker of every bin after the plan
+    pub proposed: Vec<usize>,
+    /// Estimated state size of every bin
+    pub bin_bytes: Vec<u64>,
+}
+
+impl PlanPreview {
+    /// The moves of the plan, as `(bin, target worker)`.
+    pub fn moves(&self) -> Vec<Move> {
+        plan_moves(&self.current, &self.proposed)
+    }
+
+    /// The bins whose worker changes, as `(bin, current worker, proposed worker)`.
+    pub fn diff(&self) -> Vec<(usize, usize, usize)> {
+        self.current.iter().zip(self.proposed.iter()).enumerate()
+            .filter(|(_, (current, proposed))| current != proposed)
+            .map(|(bin, (&current, &proposed))| (bin, current, proposed))
+            .collect()
+    }
+
+    /// Bins and estimated state the plan moves within processes, across processes and across hosts.
+    pub fn traffic(&self, topology: &Topology) -> Traffic {
+        topology.traffic(&self.diff(), |bin| self.bin_bytes[bin])
+    }
+
+    /// Estimated bytes of state moved by the plan.
+    pub fn moved_bytes(&self) -> u64 {
+        self.diff().iter().map(|&(bin, _, _)| self.bin_bytes[bin]).sum()
+    }
+
+    /// Per-worker bins as `(worker, bins before, bins after, bins moved out, bins moved in)`, ordered by worker.
+    pub fn workers(&self) -> Vec<(usize, usize, usize, usize, usize)> {
+        let mut workers = self.current.iter().chain(self.proposed.iter()).cloned().collect::<Vec<_>>();
+        workers.sort();
+        workers.dedup();
+        let diff = self.diff();
+        workers.into_iter().map(|worker| {
+            let before = self.current.iter().filter(|&&w| w == worker).count();
+            let after = self.proposed.iter().filter(|&&w| w == worker).count();
+            let out = diff.iter().filter(|&&(_, from, _)| from == worker).count();
+            let into = diff.iter().filter(|&&(_, _, to)| to == worker).count();
+            (worker, before, after, out, into)
+        }).collect()
+    }
+
+    /// Imbalance before and after the plan: the bins of the most loaded worker relative to the mean,
+    /// e.g. `0.5` if it has 50% more bins than the average worker. Workers that only appear after
+    /// the plan count as empty before it.
+    pub fn imbalance(&self) -> (f64, f64) {
+        let workers = self.workers();
+        let imbalance = |bins: Vec<usize>| {
+            let mean = bins.iter().sum::<usize>() as f64 / bins.len() as f64;
+            let max = bins.iter().cloned().max().unwrap_or(0) as f64;
+            if mean > 0. { max / mean - 1. } else { 0. }
+        };
+        (imbalance(workers.iter().map(|w| w.1).collect()), imbalance(workers.iter().map(|w| w.2).collect()))
+    }
+}
+
+impl fmt::Display for PlanPreview {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let diff = self.diff();
+        let (before, after) = self.imbalance();
+        writeln!(f, "{} of {} bins moved, ~{} bytes of state", diff.len(), self.current.len(), self.moved_bytes())?;
+        writeln!(f, "imbalance: {:.1}% -> {:.1}%", before * 100., after * 100.)?;
+        for (worker, before, after, out, into) in self.workers() {
+            writeln!(f, "  worker {:>3}: {:>5} -> {:>5} bins  (-{} +{})", worker, before, after, out, into)?;
+        }
+        for (bin, current, proposed) in diff {
+            writeln!(f, "  bin {:>5}: worker {} -> worker {}", bin, current, proposed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The waves of a migration plan still to be issued.
+#[derive(Debug, Clone)]
+pub struct MigrationSchedule {
+    waves: VecDeque<Vec<Move>>,
+    pacing: Pacing,
+    /// Time and control timestamp of the last issued wave
+    last: Option<(u64, usize)>,
+    /// Bins moved by the issued waves
+    issued: Vec<usize>,
+}
+
+impl MigrationSchedule {
+    pub fn new(waves: Vec<Vec<Move>>, pacing: Pacing) -> Self {
+        MigrationSchedule { waves: waves.into(), pacing, last: None, issued: Vec::new() }
+    }
+
+    /// Number of waves still to be issued.
+    pub fn remaining(&self) -> usize {
+        self.waves.len()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.waves.is_empty()
+    }
+
+    /// Number of distinct bins moved by the waves issued so far.
+    pub fn issued(&self) -> usize {
+        let mut bins = self.issued.clone();
+        bins.sort();
+        bins.dedup();
+        bins.len()
+    }
+
+    /// The next wave, if it is due at time `now`. `acknowledged` tells whether the configuration
+    /// sent at a control timestamp was applied. The caller sends the wave at `control_time`, and
+    /// must send the following wave at a later timestamp.
+    pub fn next_wave<A: Fn(usize) -> bool>(&mut self, now: u64, control_time: usize, acknowledged: A) -> Option<Vec<Move>> {
+        let due = match (self.last, self.pacing) {
+            (None, _) | (_, Pacing::Immediate) => true,
+            (Some((_, time)), Pacing::Acknowledged) => acknowledged(time),
+            (Some((issued, _)), Pacing::Interval(interval)) => now >= issued + interval,
+        };
+        if !due {
+            return None;
+        }
+        let wave = self.waves.pop_front()?;
+        self.last = Some((now, control_time));
+        self.issued.extend(wave.iter().map(|&(bin, _)| bin));
+        Some(wave)
+    }
+
+    /// Whether every wave was issued and the last one acknowledged, i.e. the plan is complete.
+    pub fn is_committed<A: Fn(usize) -> bool>(&self, acknowledged: A) -> bool {
+        self.is_done() && self.last.map(|(_, time)| acknowledged(time)).unwrap_or(true)
+    }
+
+    /// Abort the plan: drop the waves not issued yet and return the moves bringing every issued
+    /// bin back to `before(bin)`, its worker before the plan.
+    pub fn rollback<B: Fn(usize) -> usize>(&mut self, before: B) -> Vec<Move> {
+        self.waves.clear();
+        let mut bins = std::mem::take(&mut self.issued);
+        bins.sort();
+        bins.dedup();
+        bins.into_iter().map(|bin| (bin, before(bin))).collect()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn split_waves_limits() {
+        use crate::migration::{split_waves, WaveLimits};
+
+        let moves = (0..5).map(|bin| (bin, 9)).collect::<Vec<_>>();
+        let unlimited = WaveLimits { max_bins: None, max_bytes: None };
+        assert_eq!(split_waves(moves.clone(), unlimited, |_| 10).len(), 1);
+
+        let bins = WaveLimits { max_bins: Some(2), max_bytes: None };
+        let waves = split_waves(moves.clone(), bins, |_| 10);
+        assert_eq!(waves.iter().map(|w| w.len()).collect::<Vec<_>>(), vec![2, 2, 1]);
+
+        // bin 1 is larger than the limit and gets a wave of its own
+        let bytes = WaveLimits { max_bins: None, max_bytes: Some(25) };
+        let waves = split_waves(moves, bytes, |bin| if bin == 1 { 100 } else { 10 });
+        assert_eq!(waves, vec![vec![(0, 9)], vec![(1, 9)], vec![(2, 9), (3, 9)], vec![(4, 9)]]);
+    }
+
+    #[test]
+    fn bin_sizes_follow_records() {
+        use crate::migration::{bin_bytes, bin_sizes, STATE_ENTRY_BYTES};
+
+        assert_eq!(bin_bytes(1000, 16), 62 * STATE_ENTRY_BYTES);
+        assert_eq!(bin_bytes(4, 16), STATE_ENTRY_BYTES);
+        assert_eq!(bin_sizes(400, &[0, 0, 0, 0]), vec![100, 100, 100, 100]);
+        assert_eq!(bin_sizes(400, &[30, 10, 0, 0]), vec![300, 100, 0, 0]);
+    }
+
+    #[test]
+    fn schedule_pacing() {
+        use crate::migration::{MigrationSchedule, Pacing};
+
+        let waves = vec![vec![(0, 1)], vec![(1, 1)], vec![(2, 1)]];
+        let mut acked = MigrationSchedule::new(waves.clone(), Pacing::Acknowledged);
+        assert_eq!(acked.next_wave(0, 10, |_| false), Some(vec![(0, 1)]));
+        assert_eq!(acked.next_wave(5, 11, |t| t < 10), None);
+        assert_eq!(acked.next_wave(6, 12, |t| t <= 10), Some(vec![(1, 1)]));
+
+        let mut paced = MigrationSchedule::new(waves, "100ms".parse().unwrap());
+        assert_eq!(paced.next_wave(0, 0, |_| false), Some(vec![(0, 1)]));
+        assert_eq!(paced.next_wave(99_999_999, 0, |_| true), None);
+        assert_eq!(paced.next_wave(100_000_000, 0, |_| false), Some(vec![(1, 1)]));
+        assert_eq!(paced.remaining(), 1);
+    }
+
+    #[test]
+    fn schedule_rollback() {
+        use crate::migration::{MigrationSchedule, Pacing};
+
+        let waves = vec![vec![(0, 2), (1, 2)], vec![(2, 2)]];
+        let mut schedule = MigrationSchedule::new(waves, Pacing::Acknowledged);
+        assert_eq!(schedule.next_wave(0, 10, |_| false).map(|w| w.len()), Some(2));
+        assert!(!schedule.is_committed(|_| true));
+        assert_eq!((schedule.issued(), schedule.remaining()), (2, 1));
+        // the first wave was never acknowledged: bins 0 and 1 go back to worker 0, bin 2 was not moved
+        assert_eq!(schedule.rollback(|_| 0), vec![(0, 0), (1, 0)]);
+        assert!(schedule.is_done());
+    }
+
+    #[test]
+    fn plan_preview() {
+        use crate::migration::{plan_moves, PlanPreview};
+
+        assert_eq!(plan_moves(&[2, 2, 2, 2], &[0, 2, 1, 2]), vec![(0, 0), (2, 1)]);
+        assert!(plan_moves(&[0, 1], &[0, 1]).is_empty());
+
+        let preview = PlanPreview { current: vec![0, 1, 0, 1], proposed: vec![0, 1, 2, 2], bin_bytes: vec![10, 20, 30, 40] };
+        assert_eq!(preview.diff(), vec![(2, 0, 2), (3, 1, 2)]);
+        assert_eq!(preview.moves(), vec![(2, 2), (3, 2)]);
+        assert_eq!(preview.moved_bytes(), 70);
+        assert_eq!(preview.workers(), vec![(0, 2, 1, 1, 0), (1, 2, 1, 1, 0), (2, 0, 2, 0, 2)]);
+        let (before, after) = preview.imbalance();
+        assert!((before - 0.5).abs() < 1e-9 && (after - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn strategies() {
+        use crate::migration::{split_waves, Pacing, Strategy, WaveLimits};
+
+        let moves = (0..5).map(|bin| (bin, 9)).collect::<Vec<_>>();
+        let limits = WaveLimits { max_bins: None, max_bytes: None };
+        let waves = |strategy: &str| {
+            let strategy = strategy.parse::<Strategy>().unwrap();
+            split_waves(moves.clone(), strategy.limits(limits), |_| 1).iter().map(|w| w.len()).collect::<Vec<_>>()
+        };
+        assert_eq!(waves("all-at-once"), vec![5]);
+        assert_eq!(waves("fluid"), vec![1, 1, 1, 1, 1]);
+        assert_eq!(waves("batched:3"), vec![3, 2]);
+        assert_eq!(Strategy::Batched(3).limits(WaveLimits { max_bins: Some(2), max_bytes: None }).max_bins, Some(2));
+        assert_eq!(Strategy::Fluid.default_pacing(), Pacing::Immediate);
+        assert!("batched:0".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Choosing the bootstrap server of a joining process, and where bins go.
+//!
+//! The bootstrap server sends its progress state to the new process, which slows it down while
+//! the new process joins. `JoinPolicy` picks it among the current workers (see `JoinPolicy::select`).
+//!
+//! Moving a bin between the workers of a process is far cheaper than moving it to another process,
+//! let alone to another host. A `Topology` records the process and host of every worker, so that
+//! `LoadBalancer` prefers moves between close workers and keeps neighbouring bins in the same process,
+//! and so that the `Traffic` of a plan can be reported.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::metrics::WorkerBucket;
+
+/// How the bootstrap server of a joining process is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinPolicy {
+    /// Always the given worker
+    Fixed(usize),
+    /// The workers in turn
+    RoundRobin,
+    /// The worker that processed the fewest records recently
+    LeastRecords,
+    /// The worker with the lowest recent latency, i.e. the one whose frontier lags the least
+    LeastLag,
+    /// The workers in turn, skipping the ones serving a join in progress
+    Spread,
+}
+
+impl FromStr for JoinPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(JoinPolicy::RoundRobin),
+            "least-records" => Ok(JoinPolicy::LeastRecords),
+            "least-lag" => Ok(JoinPolicy::LeastLag),
+            "spread" => Ok(JoinPolicy::Spread),
+            _ => match s.strip_prefix("fixed:").map(|w| w.parse()) {
+                Some(Ok(worker)) => Ok(JoinPolicy::Fixed(worker)),
+                _ => Err(format!("unknown join policy {:?}, expected one of fixed:WORKER, round-robin, least-records, least-lag, spread", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for JoinPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinPolicy::Fixed(worker) => write!(f, "fixed:{}", worker),
+            JoinPolicy::RoundRobin => write!(f, "round-robin"),
+            JoinPolicy::LeastRecords => write!(f, "least-records"),
+            JoinPolicy::LeastLag => write!(f, "least-lag"),
+            JoinPolicy::Spread => write!(f, "spread"),
+        }
+    }
+}
+
+/// How the load balancer places bins, see `LoadBalancer::set_topology`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Prefer moves within a process, then within a host, and keep neighbouring bins in the same process
+    Topology,
+    /// Ignore where the workers run
+    Flat,
+    /// Ignore where the workers run, and move the bins that balance the records observed so far best
+    Records,
+}
+
+impl FromStr for Placement {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "topology" => Ok(Placement::Topology),
+            "flat" => Ok(Placement::Flat),
+            "records" => Ok(Placement::Records),
+            _ => Err(format!("unknown bin placement {:?}, expected one of topology, flat, records", s)),
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Placement::Topology => write!(f, "topology"),
+            Placement::Flat => write!(f, "flat"),
+            Placement::Records => write!(f, "records"),
+        }
+    }
+}
+
+impl JoinPolicy {
+    /// Pick the bootstrap server among the workers `0..peers`.
+    /// * `turn`: number of servers selected before
+    /// * `load`: recent records and latency of every worker, workers without an entry are idle
+    /// * `busy`: servers of the joins in progress, avoided unless every worker is busy
+    ///
+    /// Fails if the worker of a `Fixed` policy is not one of the workers.
+    pub fn select(&self, peers: usize, turn: usize, load: &HashMap<usize, WorkerBucket>, busy: &[usize]) -> Result<usize, String> {
+        assert!(peers > 0);
+        let idle = WorkerBucket::default();
+        let candidates = (0..peers).filter(|w| !busy.contains(w)).collect::<Vec<_>>();
+        let candidates = if candidates.is_empty() { (0..peers).collect() } else { candidates };
+        let server = match *self {
+            JoinPolicy::Fixed(worker) if worker >= peers => return Err(format!("bootstrap server {} is not one of the {} workers", worker, peers)),
+            JoinPolicy::Fixed(worker) => worker,
+            JoinPolicy::RoundRobin => turn % peers,
+            JoinPolicy::LeastRecords => *candidates.iter()
+                .min_by_key(|w| load.get(w).unwrap_or(&idle).records)
+                .unwrap(),
+            JoinPolicy::LeastLag => *candidates.iter()
+                .min_by_key(|w| load.get(w).unwrap_or(&idle).mean_latency())
+                .unwrap(),
+            JoinPolicy::Spread => (turn..turn + peers)
+                .map(|w| w % peers)
+                .find(|w| candidates.contains(w))
+                .unwrap(),
+        };
+        Ok(server)
+    }
+}
+
+/// The process of every worker and the host of every process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// Worker indices of every process
+    processes: Vec<Range<usize>>,
+    /// Host of every process
+    hosts: Vec<String>,
+}
+
+/// How far a bin travels when it moves between two workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Distance {
+    IntraProcess,
+    CrossProcess,
+    CrossHost,
+}
+
+/// Bins and estimated bytes of state moved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Volume {
+    pub bins: usize,
+    pub bytes: u64,
+}
+
+/// The bins moved by a plan, by `Distance`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub intra_process: Volume,
+    /// Between processes on the same host
+    pub cross_process: Volume,
+    pub cross_host: Volume,
+}
+
+impl Topology {
+    /// Processes with the worker indices `processes`, running on `hosts`.
+    pub fn new(processes: Vec<Range<usize>>, hosts: Vec<String>) -> Self {
+        assert_eq!(processes.len(), hosts.len(), "one host per process");
+        Topology { processes, hosts }
+    }
+
+    /// `processes` processes of `workers` workers each, on the local host.
+    pub fn uniform(processes: usize, workers: usize) -> Self {
+        Topology::new((0..processes).map(|p| p * workers..(p + 1) * workers).collect(), vec!["localhost".to_string(); processes])
+    }
+
+    /// The process `worker` belongs to.
+    pub fn process_of(&self, worker: usize) -> Option<usize> {
+        self.processes.iter().position(|r| r.contains(&worker))
+    }
+
+    /// The host `worker` runs on.
+    pub fn host_of(&self, worker: usize) -> Option<&str> {
+        self.process_of(worker).map(|p| self.hosts[p].as_str())
+    }
+
+    /// How far a bin travels from worker `from` to worker `to`. Workers outside the topology are
+    /// assumed to run on a host of their own.
+    pub fn distance(&self, from: usize, to: usize) -> Distance {
+        match (self.process_of(from), self.process_of(to)) {
+            (Some(a), Some(b)) if a == b => Distance::IntraProcess,
+            (Some(a), Some(b)) if self.hosts[a] == self.hosts[b] => Distance::CrossProcess,
+            _ if from == to => Distance::IntraProcess,
+            _ => Distance::CrossHost,
+        }
+    }
+
+    /// The traffic of the moves `diff`, as `(bin, from, to)`, where `bin_bytes` estimates the state of a bin.
+    pub fn traffic<F: Fn(usize) -> u64>(&self, diff: &[(usize, usize, usize)], bin_bytes: F) -> Traffic {
+        let mut traffic = Traffic::default();
+        for &(bin, from, to) in diff.iter() {
+            let volume = match self.distance(from, to) {
+                Distance::IntraProcess => &mut traffic.intra_process,
+                Distance::CrossProcess => &mut traffic.cross_process,
+                Distance::CrossHost => &mut traffic.cross_host,
+            };
+            volume.bins += 1;
+            volume.bytes += bin_bytes(bin);
+        }
+        traffic
+    }
+}
+
+impl fmt::Display for Traffic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "traffic: {} bins (~{} bytes) within processes, {} bins (~{} bytes) across processes, {} bins (~{} bytes) across hosts",
+                 self.intra_process.bins, self.intra_process.bytes, self.cross_process.bins, self.cross_process.bytes,
+                 self.cross_host.bins, self.cross_host.bytes)
+    }
+}
+
+mod test {
+
+    #[test]
+    fn join_policy_select() {
+        use crate::placement::{JoinPolicy, Placement};
+        use crate::metrics::WorkerBucket;
+
+        let load = vec![
+            (0, WorkerBucket { records: 30, latency_sum: 300, latency_max: 20 }),
+            (1, WorkerBucket { records: 10, latency_sum: 500, latency_max: 90 }),
+            (2, WorkerBucket { records: 20, latency_sum: 20, latency_max: 2 }),
+        ].into_iter().collect();
+
+        assert_eq!(JoinPolicy::Fixed(2).select(4, 0, &load, &[]), Ok(2));
+        assert_eq!(JoinPolicy::RoundRobin.select(4, 5, &load, &[]), Ok(1));
+        // worker 3 has no entry: it is idle
+        assert_eq!(JoinPolicy::LeastRecords.select(4, 0, &load, &[]), Ok(3));
+        assert_eq!(JoinPolicy::LeastRecords.select(3, 0, &load, &[]), Ok(1));
+        assert_eq!(JoinPolicy::LeastRecords.select(3, 0, &load, &[1]), Ok(2));
+        assert_eq!(JoinPolicy::LeastLag.select(3, 0, &load, &[]), Ok(2));
+        assert_eq!(JoinPolicy::Spread.select(3, 1, &load, &[1, 2]), Ok(0));
+        assert_eq!(JoinPolicy::Spread.select(3, 1, &load, &[0, 1, 2]), Ok(1));
+        assert!(JoinPolicy::Fixed(4).select(4, 0, &load, &[]).is_err());
+
+        assert_eq!("fixed:3".parse::<JoinPolicy>(), Ok(JoinPolicy::Fixed(3)));
+        assert_eq!("least-lag".parse::<JoinPolicy>().map(|p| p.to_string()), Ok("least-lag".to_string()));
+        assert!("fixed:x".parse::<JoinPolicy>().is_err());
+
+        assert_eq!("flat".parse::<Placement>(), Ok(Placement::Flat));
+        assert_eq!(Placement::Topology.to_string(), "topology");
+        assert!("nearest".parse::<Placement>().is_err());
+    }
+
+    #[test]
+    fn topology_traffic() {
+        use crate::placement::{Distance, Topology, Volume};
+
+        let topology = Topology::new(vec![0..2, 2..4, 4..5], vec!["a".to_string(), "a".to_string(), "b".to_string()]);
+        assert_eq!(topology.distance(0, 1), Distance::IntraProcess);
+        assert_eq!(topology.distance(1, 3), Distance::CrossProcess);
+        assert_eq!(topology.distance(3, 4), Distance::CrossHost);
+        assert_eq!(topology.distance(0, 9), Distance::CrossHost);
+
+        let traffic = topology.traffic(&[(0, 0, 1), (1, 0, 2), (2, 2, 4), (3, 3, 4)], |bin| 10 * bin as u64);
+        assert_eq!(traffic.intra_process, Volume { bins: 1, bytes: 0 });
+        assert_eq!(traffic.cross_process, Volume { bins: 1, bytes: 10 });
+        assert_eq!(traffic.cross_host, Volume { bins: 2, bytes: 50 });
+        assert_eq!(Topology::uniform(2, 3).process_of(4), Some(1));
+    }
+}
+
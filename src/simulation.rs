@@ -0,0 +1,273 @@
+//! Replaying a per-bin load trace against `LoadBalancer` policies, without running a dataflow.
+//!
+//! A `Trace` holds the records of every bin in successive intervals, either recorded by the
+//! benchmark (its `bin_load` records, see `Trace::from_metrics`) or generated (`Trace::synthetic`).
+//! `simulate` starts from the processes of a `Scenario`, adds processes at its rescale times and
+//! lets the load balancer plan every rescale. For every rescale it reports the moves, the state
+//! they migrate and the load imbalance of the workers before and after it. Only `Placement::Records`
+//! plans from the trace (the records up to the rescale), the other placements only from bin counts.
+
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::LoadBalancer;
+use crate::metrics::MetricsFile;
+use crate::migration::{bin_sizes, PlanPreview};
+use crate::placement::{Placement, Topology, Traffic};
+
+/// Records of every bin over time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub bins: usize,
+    /// Records of every bin in the interval ending at `time`, as `(time, records)`, by time
+    pub samples: Vec<(u64, Vec<u64>)>,
+}
+
+impl Trace {
+    /// The `bin_load` records of a benchmark run, as `(time, bin, records)`.
+    pub fn from_metrics(metrics: &MetricsFile) -> Result<Self, String> {
+        let parse = |field: &String| field.parse::<u64>().map_err(|e| format!("invalid field {:?}: {}", field, e));
+
+        let mut records = Vec::new();
+        for fields in metrics.records("bin_load") {
+            if fields.len() < 3 {
+                return Err(format!("short bin_load record: {:?}", fields));
+            }
+            records.push((parse(&fields[0])?, parse(&fields[1])? as usize, parse(&fields[2])?));
+        }
+        if records.is_empty() {
+            return Err("no bin_load records".to_string());
+        }
+        let bins = match metrics.metadata.get("bins") {
+            Some(bins) => bins.parse().map_err(|e| format!("invalid bins {:?}: {}", bins, e))?,
+            None => records.iter().map(|&(_, bin, _)| bin + 1).max().unwrap(),
+        };
+
+        let mut samples: Vec<(u64, Vec<u64>)> = Vec::new();
+        records.sort_by_key(|&(time, bin, _)| (time, bin));
+        for (time, bin, count) in records {
+            if bin >= bins {
+                return Err(format!("bin {} out of range, the run has {} bins", bin, bins));
+            }
+            if samples.last().map(|(t, _)| *t != time).unwrap_or(true) {
+                samples.push((time, vec![0; bins]));
+            }
+            samples.last_mut().unwrap().1[bin] += count;
+        }
+        Ok(Trace { bins, samples })
+    }
+
+    /// `rate` records per second over `duration_ns`, in intervals of `interval_ns`. The share of a bin
+    /// follows a Zipf distribution with exponent `skew` (0 is uniform) over bins ranked at random,
+    /// and varies by up to 10% from one interval to the next.
+    pub fn synthetic(bins: usize, duration_ns: u64, interval_ns: u64, rate: u64, skew: f64, seed: u64) -> Self {
+        assert!(bins > 0 && interval_ns > 0);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut ranks = (0..bins).collect::<Vec<_>>();
+        ranks.shuffle(&mut rng);
+        let weights = ranks.iter().map(|&rank| 1. / ((rank + 1) as f64).powf(skew)).collect::<Vec<_>>();
+        let total = weights.iter().sum::<f64>();
+        let per_interval = rate as f64 * interval_ns as f64 / 1_000_000_000.;
+
+        let samples = (1..=duration_ns / interval_ns)
+            .map(|i| {
+                let records = weights.iter()
+                    .map(|weight| (per_interval * weight / total * rng.gen_range(0.9, 1.1)).round() as u64)
+                    .collect();
+                (i * interval_ns, records)
+            })
+            .collect();
+        Trace { bins, samples }
+    }
+}
+
+/// The cluster a trace is replayed on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    /// Initial number of processes
+    pub processes: usize,
+    /// Worker threads of every process
+    pub threads: usize,
+    /// Processes added by every rescale, as `(time, processes)`, by time
+    pub rescales: Vec<(u64, usize)>,
+    /// Capacity weights, see `LoadBalancer::set_capacity`
+    pub capacities: Vec<(usize, f64)>,
+    /// Estimated state of all bins, split among them in proportion to the records of the samples
+    /// before every plan (see `migration::bin_sizes`)
+    pub state_bytes: u64,
+}
+
+/// The outcome of a rescale, or of the initial plan at time 0 if the capacities call for one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RescaleReport {
+    pub time: u64,
+    /// Workers after the rescale
+    pub workers: usize,
+    pub moves: usize,
+    pub traffic: Traffic,
+    /// Mean imbalance (max / mean load - 1) of the samples since the previous rescale, `None` without samples
+    pub imbalance_before: Option<f64>,
+    /// Mean imbalance of the samples until the next rescale
+    pub imbalance_after: Option<f64>,
+}
+
+impl RescaleReport {
+    /// Estimated bytes of state moved.
+    pub fn bytes(&self) -> u64 {
+        self.traffic.intra_process.bytes + self.traffic.cross_process.bytes + self.traffic.cross_host.bytes
+    }
+}
+
+/// The outcome of replaying a trace with a placement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub placement: Placement,
+    pub rescales: Vec<RescaleReport>,
+    /// Mean imbalance over all the samples
+    pub imbalance: Option<f64>,
+}
+
+/// The imbalance of the workers `0..workers` given the records of every bin: the highest load per unit
+/// of capacity over the mean one, minus 1. `None` if no records were processed.
+fn imbalance(load_balancer: &LoadBalancer, workers: usize, records: &[u64]) -> Option<f64> {
+    let mut loads = vec![0; workers];
+    for (bin, worker) in load_balancer.assignment().into_iter().enumerate() {
+        loads[worker] += records[bin];
+    }
+    let total = loads.iter().sum::<u64>();
+    let capacity = (0..workers).map(|worker| load_balancer.capacity(worker)).sum::<f64>();
+    if total == 0 || capacity == 0. {
+        return None;
+    }
+    let max = loads.iter().enumerate()
+        .map(|(worker, &load)| match load_balancer.capacity(worker) {
+            c if c > 0. => load as f64 / c,
+            _ if load > 0 => f64::INFINITY,
+            _ => 0.,
+        })
+        .fold(0., f64::max);
+    Some(max / (total as f64 / capacity) - 1.)
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) }
+}
+
+/// Replay `trace` on `scenario`, planning every rescale with `placement`. A plan is assumed to be applied
+/// at once, at the time of its rescale: the samples ending until then are processed by the previous workers.
+pub fn simulate(trace: &Trace, scenario: &Scenario, placement: Placement) -> Simulation {
+    let mut processes = scenario.processes;
+    let peers = |processes: usize| processes * scenario.threads;
+    let mut load_balancer = LoadBalancer::new((0..peers(processes)).collect(), trace.bins);
+    for &(worker, capacity) in scenario.capacities.iter() {
+        load_balancer.set_capacity(worker, capacity);
+    }
+
+    let mut reports: Vec<RescaleReport> = Vec::new();
+    // imbalance of the samples since the last plan, and of all the samples before
+    let mut window = Vec::new();
+    let mut all = Vec::new();
+    let mut bin_records = vec![0; trace.bins];
+    let mut samples = trace.samples.iter().peekable();
+    for (time, added) in std::iter::once((0, 0)).chain(scenario.rescales.iter().cloned()) {
+        while let Some((t, records)) = samples.peek() {
+            if *t > time {
+                break;
+            }
+            window.extend(imbalance(&load_balancer, peers(processes), records));
+            for (total, records) in bin_records.iter_mut().zip(records.iter()) {
+                *total += records;
+            }
+            samples.next();
+        }
+
+        let new_workers = peers(processes)..peers(processes + added);
+        processes += added;
+        let topology = Topology::uniform(processes, scenario.threads);
+        match placement {
+            Placement::Topology => load_balancer.set_topology(topology.clone()),
+            Placement::Records => load_balancer.set_bin_loads(bin_records.clone()),
+            Placement::Flat => {},
+        }
+        let snapshot = load_balancer.clone();
+        let moves = load_balancer.add_workers(new_workers.collect()).count();
+        // the initial workers only get a plan if their capacities call for one
+        if added == 0 && moves == 0 {
+            continue;
+        }
+
+        let imbalance_before = mean(&window);
+        if let Some(previous) = reports.last_mut() {
+            previous.imbalance_after = imbalance_before;
+        }
+        all.append(&mut window);
+        let preview = PlanPreview { current: snapshot.assignment(), proposed: load_balancer.assignment(), bin_bytes: bin_sizes(scenario.state_bytes, &bin_records) };
+        reports.push(RescaleReport {
+            time,
+            workers: peers(processes),
+            moves,
+            traffic: preview.traffic(&topology),
+            imbalance_before,
+            imbalance_after: None,
+        });
+    }
+    for (_, records) in samples {
+        window.extend(imbalance(&load_balancer, peers(processes), records));
+    }
+    if let Some(previous) = reports.last_mut() {
+        previous.imbalance_after = mean(&window);
+    }
+    all.append(&mut window);
+
+    Simulation { placement, rescales: reports, imbalance: mean(&all) }
+}
+
+mod test {
+
+    #[test]
+    fn simulate_rescales() {
+        use crate::placement::Placement;
+        use crate::simulation::{simulate, Scenario, Trace};
+
+        // bin 0 is twice as hot as the others
+        let samples = (1..=4).map(|i| (i * 10, vec![20, 10, 10, 10, 10, 10, 10, 10])).collect();
+        let trace = Trace { bins: 8, samples };
+        let scenario = Scenario { processes: 1, threads: 2, rescales: vec![(20, 1)], capacities: vec![], state_bytes: 900 };
+
+        let flat = simulate(&trace, &scenario, Placement::Flat);
+        assert_eq!(flat.rescales.len(), 1);
+        let rescale = &flat.rescales[0];
+        assert_eq!((rescale.time, rescale.workers, rescale.moves, rescale.bytes()), (20, 4, 4, 400));
+        assert_eq!(rescale.traffic.cross_process.bins, 4);
+        // worker 0 has bins 0, 2, 4, 6 and then 0, 2: 50 of 90 records, then 30 of 90
+        assert_eq!(rescale.imbalance_before.map(|i| (i * 90.).round()), Some(10.));
+        assert_eq!(rescale.imbalance_after.map(|i| (i * 90.).round()), Some(30.));
+        assert_eq!(flat.imbalance.map(|i| (i * 90.).round()), Some(20.));
+
+        // a worker of capacity 0 is drained by an initial plan
+        let scenario = Scenario { capacities: vec![(1, 0.)], rescales: vec![], ..scenario };
+        let drained = simulate(&trace, &scenario, Placement::Topology);
+        assert_eq!(drained.rescales.len(), 1);
+        assert_eq!((drained.rescales[0].time, drained.rescales[0].moves), (0, 4));
+        assert_eq!(drained.rescales[0].traffic.intra_process.bins, 4);
+        assert_eq!(drained.imbalance, Some(0.));
+
+        // bins 0 and 2 are twice as hot as the others: the same plan size, but the recorded loads
+        // move bin 2 away from bin 0 instead of leaving both on worker 0
+        let samples = (1..=4).map(|i| (i * 10, vec![20, 10, 20, 10, 10, 10, 10, 10])).collect();
+        let trace = Trace { bins: 8, samples };
+        let scenario = Scenario { processes: 1, threads: 2, rescales: vec![(20, 1)], capacities: vec![], state_bytes: 1000 };
+        let flat = simulate(&trace, &scenario, Placement::Flat);
+        let records = simulate(&trace, &scenario, Placement::Records);
+        assert_eq!((flat.rescales[0].moves, records.rescales[0].moves), (4, 4));
+        assert_eq!((flat.rescales[0].bytes(), records.rescales[0].bytes()), (400, 500));
+        assert_eq!(flat.rescales[0].imbalance_after.map(|i| (i * 100.).round()), Some(60.));
+        assert_eq!(records.rescales[0].imbalance_after.map(|i| (i * 100.).round()), Some(20.));
+
+        let synthetic = Trace::synthetic(16, 10_000_000_000, 1_000_000_000, 1000, 1., 7);
+        assert_eq!(synthetic.samples.len(), 10);
+        assert_eq!(synthetic, Trace::synthetic(16, 10_000_000_000, 1_000_000_000, 1000, 1., 7));
+    }
+}
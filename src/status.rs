@@ -0,0 +1,187 @@
+//! Observing the bin map of a running dataflow.
+//!
+//! The routing table of the stateful operators is internal to Megaphone, so the controller's model
+//! (`LoadBalancer`) can drift from it, e.g. after controls sent by hand. On a status request, every
+//! worker reports the bins whose records it processed since the previous request and how many; the
+//! worker that processed the records of a bin is its owner (see `bin_status`). Bins without records
+//! in the interval are not observed.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use timely::Data;
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::Operator;
+use timely::dataflow::operators::exchange::Exchange;
+
+use dynamic_scaling_mechanism::BIN_SHIFT;
+
+/// The bin of a key with hash `hash`, as computed by the stateful operators.
+pub fn bin_of(hash: u64) -> usize {
+    (hash >> (64 - BIN_SHIFT)) as usize
+}
+
+/// Bin map and per-bin record counts observed by a status request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    /// Identifier of the request
+    pub request: u64,
+    /// Worker that processed the records of every bin, `None` if the bin had no records or conflicts
+    pub owners: Vec<Option<usize>>,
+    /// Records of every bin processed since the previous request
+    pub records: Vec<u64>,
+    /// Bins whose records were processed by more than one worker, i.e. that moved during the interval
+    pub conflicts: Vec<usize>,
+}
+
+impl Status {
+    /// Merge the reports of the workers, as `(worker, [(bin, records)])`.
+    pub fn from_reports(request: u64, bins: usize, reports: &[(usize, Vec<(usize, u64)>)]) -> Self {
+        let mut owners = vec![None; bins];
+        let mut records = vec![0; bins];
+        let mut conflicts = Vec::new();
+        for (worker, counts) in reports.iter() {
+            for &(bin, count) in counts.iter() {
+                records[bin] += count;
+                match owners[bin] {
+                    None if !conflicts.contains(&bin) => owners[bin] = Some(*worker),
+                    Some(owner) if owner != *worker => {
+                        owners[bin] = None;
+                        conflicts.push(bin);
+                    },
+                    _ => {},
+                }
+            }
+        }
+        conflicts.sort();
+        Status { request, owners, records, conflicts }
+    }
+
+    /// Number of bins whose owner was observed.
+    pub fn observed(&self) -> usize {
+        self.owners.iter().filter(|owner| owner.is_some()).count()
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bins_per_worker: HashMap<usize, (usize, u64)> = HashMap::new();
+        for (owner, records) in self.owners.iter().zip(self.records.iter()) {
+            if let Some(owner) = owner {
+                let entry = bins_per_worker.entry(*owner).or_default();
+                entry.0 += 1;
+                entry.1 += records;
+            }
+        }
+        let mut workers = bins_per_worker.into_iter().collect::<Vec<_>>();
+        workers.sort();
+        writeln!(f, "status {}: {} of {} bins observed, {} records", self.request, self.observed(), self.owners.len(), self.records.iter().sum::<u64>())?;
+        for (worker, (bins, records)) in workers {
+            writeln!(f, "  worker {}: {} bins, {} records", worker, bins, records)?;
+        }
+        if !self.conflicts.is_empty() {
+            writeln!(f, "  moved during the interval: {:?}", self.conflicts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer the status requests of `requests`, which must be broadcast to every worker, with the
+/// records of `output`, the output of a stateful operator whose keys hash with `hash`.
+/// A request at time `t` covers the records up to `t` since the previous request; the merged `Status`
+/// is produced at worker 0 once all the workers reported. While no request comes, the records are
+/// kept as one count per bin.
+pub fn bin_status<G, D, H>(output: &Stream<G, D>, requests: &Stream<G, u64>, hash: H) -> Stream<G, Status>
+where
+    G: Scope<Timestamp=usize>,
+    D: Data,
+    H: Fn(&D) -> u64 + 'static,
+{
+    let index = output.scope().index();
+    let bins = 1 << BIN_SHIFT;
+
+    // records of every bin processed by this worker, by timestamp, and of the timestamps the requests
+    // frontier passed, which the next request covers whatever its time
+    let mut counts: HashMap<usize, HashMap<usize, u64>> = HashMap::new();
+    let mut passed: HashMap<usize, u64> = HashMap::new();
+    let mut pending: HashMap<usize, Vec<u64>> = HashMap::new();
+    // reports received by worker 0, by timestamp and request
+    let mut reports: HashMap<(usize, u64), Vec<(usize, Vec<(usize, u64)>)>> = HashMap::new();
+
+    output
+        .binary_notify(requests, Pipeline, Pipeline, "BinStatus", vec![], move |records, requests, output, notificator| {
+            records.for_each(|time, data| {
+                let counts = counts.entry(*time.time()).or_default();
+                for record in data.iter() {
+                    *counts.entry(bin_of(hash(record))).or_default() += 1;
+                }
+            });
+            requests.for_each(|time, data| {
+                pending.entry(*time.time()).or_default().extend(data.iter().cloned());
+                notificator.notify_at(time.retain());
+            });
+            // Fold the counts of the timestamps no pending request precedes and the requests frontier
+            // passed, so that they stay bounded by the number of bins while no request comes
+            let frontier = notificator.frontier(1).to_vec();
+            let foldable = counts.keys()
+                .filter(|t| frontier.iter().all(|f| *t <= f) && pending.keys().all(|p| p >= *t))
+                .cloned().collect::<Vec<_>>();
+            for t in foldable {
+                for (bin, records) in counts.remove(&t).unwrap() {
+                    *passed.entry(bin).or_default() += records;
+                }
+            }
+            notificator.for_each(|time, _, _| {
+                let mut covered = std::mem::take(&mut passed);
+                let times = counts.keys().filter(|t| *t <= time.time()).cloned().collect::<Vec<_>>();
+                for t in times {
+                    for (bin, records) in counts.remove(&t).unwrap() {
+                        *covered.entry(bin).or_default() += records;
+                    }
+                }
+                let mut covered = covered.into_iter().collect::<Vec<(usize, u64)>>();
+                covered.sort();
+                let mut session = output.session(&time);
+                for request in pending.remove(time.time()).unwrap_or_default() {
+                    session.give((request, index, covered.clone()));
+                }
+            });
+        })
+        .exchange(|_| 0)
+        .unary_notify(Pipeline, "BinStatusMerge", vec![], move |input, output, notificator| {
+            input.for_each(|time, data| {
+                for (request, worker, counts) in data.iter().cloned() {
+                    reports.entry((*time.time(), request)).or_default().push((worker, counts));
+                }
+                notificator.notify_at(time.retain());
+            });
+            notificator.for_each(|time, _, _| {
+                let mut complete = reports.keys().filter(|(t, _)| t == time.time()).cloned().collect::<Vec<_>>();
+                complete.sort();
+                let mut session = output.session(&time);
+                for key in complete {
+                    let reports = reports.remove(&key).unwrap();
+                    session.give(Status::from_reports(key.1, bins, &reports));
+                }
+            });
+        })
+}
+
+mod test {
+
+    #[test]
+    fn status_from_reports() {
+        use crate::status::Status;
+
+        let reports = vec![
+            (0, vec![(0, 5), (2, 1)]),
+            (1, vec![(1, 3), (2, 2)]),
+        ];
+        let status = Status::from_reports(7, 4, &reports);
+        assert_eq!(status.owners, vec![Some(0), Some(1), None, None]);
+        assert_eq!(status.records, vec![5, 3, 3, 0]);
+        assert_eq!(status.conflicts, vec![2]);
+        assert_eq!(status.observed(), 2);
+    }
+}
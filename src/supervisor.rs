@@ -0,0 +1,152 @@
+//! Supervision of the worker processes spawned during a run.
+//!
+//! The `Supervisor` owns every spawned child: it reports children that exited with an error,
+//! together with the tail of their stderr, and kills whatever is still running when the run
+//! ends, is interrupted or panics (on drop), so no timely process outlives the run.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Lines of stderr reported for a failed process.
+const STDERR_TAIL_LINES: usize = 20;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Install a Ctrl-C handler recording the interruption, see `interrupted`.
+/// Can only be called once per process.
+pub fn install_interrupt_handler() -> Result<(), String> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)).map_err(|e| format!("{}", e))
+}
+
+/// Whether Ctrl-C was pressed since `install_interrupt_handler` was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// A supervised process that exited unsuccessfully.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub process: usize,
+    pub status: String,
+    pub stderr_tail: String,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "process {} failed: {}", self.process, self.status)?;
+        for line in self.stderr_tail.lines() {
+            writeln!(f, "  | {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+struct Supervised {
+    process: usize,
+    child: Child,
+    stderr: Option<PathBuf>,
+}
+
+impl Supervised {
+    fn failure(&self, status: String) -> Failure {
+        let stderr_tail = self.stderr.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|stderr| {
+                let lines = stderr.lines().collect::<Vec<_>>();
+                lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+            })
+            .unwrap_or_default();
+        Failure { process: self.process, status, stderr_tail }
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "terminated by a signal".to_string(),
+    }
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    children: Vec<Supervised>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Supervise `child`, the process with index `process`. `stderr` is the file its stderr
+    /// is redirected to, if any, reported when it fails.
+    pub fn add(&mut self, process: usize, child: Child, stderr: Option<PathBuf>) {
+        self.children.push(Supervised { process, child, stderr });
+    }
+
+    /// Number of supervised processes still running.
+    pub fn running(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Reap the processes that exited, returning the ones that failed.
+    pub fn poll(&mut self) -> Vec<Failure> {
+        let mut failures = Vec::new();
+        let mut i = 0;
+        while i < self.children.len() {
+            match self.children[i].child.try_wait() {
+                Ok(None) => i += 1,
+                Ok(Some(status)) => {
+                    let supervised = self.children.remove(i);
+                    if !status.success() {
+                        failures.push(supervised.failure(describe(status)));
+                    }
+                },
+                Err(e) => {
+                    let supervised = self.children.remove(i);
+                    failures.push(supervised.failure(format!("cannot wait for process: {}", e)));
+                },
+            }
+        }
+        failures
+    }
+
+    /// Kill `process` if it is still running and stop supervising it.
+    pub fn kill(&mut self, process: usize) {
+        if let Some(i) = self.children.iter().position(|s| s.process == process) {
+            let mut supervised = self.children.remove(i);
+            let _ = supervised.child.kill();
+            let _ = supervised.child.wait();
+        }
+    }
+
+    /// Kill every process still running.
+    pub fn kill_all(&mut self) {
+        for mut supervised in self.children.drain(..) {
+            let _ = supervised.child.kill();
+            let _ = supervised.child.wait();
+        }
+    }
+
+    /// Wait up to `grace` for all processes to exit, then kill the remaining ones.
+    /// Returns the processes that failed, including the ones that had to be killed.
+    pub fn shutdown(&mut self, grace: Duration) -> Vec<Failure> {
+        let deadline = Instant::now() + grace;
+        let mut failures = self.poll();
+        while !self.children.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+            failures.extend(self.poll());
+        }
+        failures.extend(self.children.iter().map(|s| s.failure(format!("still running after {:?}, killed", grace))));
+        self.kill_all();
+        failures
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.kill_all();
+    }
+}
@@ -0,0 +1,60 @@
+#!/usr/bin/env bash
+
+# Run the same benchmark workload in every mode and compare the latencies:
+#   rescaled      -- start with N processes and add SPAWN_BATCH processes at 1/3 and at 2/3 of the run
+#   static        -- N processes for the whole run (initial size)
+#   static-final  -- N+2*SPAWN_BATCH processes for the whole run (final size)
+#   plain         -- N processes running plain timely `state_machine` instead of Megaphone
+
+if [[ $# -lt 2 ]]; then
+    echo "USAGE:"
+    echo "    compare.sh INITIAL_NUMBER_OF_PROCESSES WORKER_THREADS_PER_PROCESS [OUTPUT_DIR]"
+    echo "EXAMPLE:"
+    echo "    compare.sh 2 1 results"
+    exit 1
+fi
+
+n="$1"
+w="$2"
+out="${3:-compare-$(date +%Y%m%d-%H%M%S)}"
+duration=${DURATION_S:-40}
+# the rescaled run adds SPAWN_BATCH processes at each of its two rescales
+batch=${SPAWN_BATCH:-1}
+final=$((n+2*batch))
+
+mkdir -p "${out}"
+cargo build --release --bin benchmark --bin analyze --bin hostfile || exit 1
+
+# run_mode MODE METRICS_NAME NUMBER_OF_PROCESSES
+# status requests are off in every mode, plain timely has no bins to report and the overhead must compare like with like
+function run_mode() {
+    echo "Running $1 with $3 processes"
+    # every run gets its own ports, so that leftovers of a previous run cannot interfere
+    target/release/hostfile create "${out}/$2-hosts" $3 || exit 1
+    pids=()
+    for p in `seq 0 $(($3-1))`; do
+        N=$3 W=${w} MODE=$1 STATUS_INTERVAL_S=0 DURATION_S=${duration} METRICS="${out}/$2" HOSTFILE="${out}/$2-hosts" RUN_DIR="${out}/$2-spawned" \
+            target/release/benchmark -n $3 -w ${w} -p ${p} -h "${out}/$2-hosts" > "${out}/$2-process-${p}.log" 2>&1 &
+        pids+=($!)
+    done
+    for pid in ${pids[@]}; do
+        wait ${pid} || echo "process ${pid} of the $2 run failed"
+    done
+}
+
+run_mode rescaled rescaled ${n}
+run_mode static static ${n}
+run_mode static static-final ${final}
+run_mode plain plain ${n}
+
+analyze=target/release/analyze
+third=$((duration/3))
+echo
+${analyze} "${out}/rescaled" --svg "${out}/rescaled.svg" | tee "${out}/kpis.txt"
+echo
+echo "== Rescaled vs static at the initial size, before the first rescale"
+${analyze} "${out}/rescaled" --baseline "${out}/static" --from 2 --to ${third} | tail -8 | tee -a "${out}/kpis.txt"
+echo "== Rescaled vs static at the final size, after the last rescale"
+${analyze} "${out}/rescaled" --baseline "${out}/static-final" --from $((2*third+2)) --to $((duration-2)) | tail -8 | tee -a "${out}/kpis.txt"
+echo "== Megaphone vs plain timely, same static cluster"
+${analyze} "${out}/static" --baseline "${out}/plain" --from 2 --to $((duration-2)) | tail -8 | tee -a "${out}/kpis.txt"
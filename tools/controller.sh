@@ -19,13 +19,51 @@ bin="$1"
 n="$2"
 w="$3"
 p=${n} # process indices in increasing sequence
+joins=0 # number of spawned processes, for JOIN_POLICY=round-robin
+map=() # worker of every bin, following the moves sent by this controller, for `preview`
+# With $HOSTFILE (the hostfile the initial processes were started with, see `cargo run --bin hostfile`)
+# every spawned process is appended to it with a free local port
+host_args=""
+if ! [[ -z "${HOSTFILE}" ]]; then
+    host_args="-h ${HOSTFILE}"
+fi
+pids=() # spawned worker processes (without tmux) by process index, until reported as exited
+windows=() # tmux windows of the spawned worker processes, until reported as closed
+
+# Start from the initial round-robin map of the initial workers, the first time it is needed
+function init_map() {
+    if [[ ${#map[@]} -eq 0 ]]; then
+        IFS=, read -r -a map <<< "$(cargo run -q --bin plan -- --workers $((n*w)) --print-map)"
+    fi
+}
+
+# Kill the spawned worker processes when the controller exits, so that none outlives the session
+function teardown() {
+    for pid in ${pids[@]}; do
+        kill ${pid} 2>/dev/null
+    done
+    for win in ${windows[@]}; do
+        tmux kill-window -t ${win} 2>/dev/null
+    done
+}
+trap teardown EXIT
+trap "exit 130" INT TERM
 
 function help() {
     echo "---------"
     echo "Commands:"
     echo "  move BIN_ID TARGET_WORKER -- move ownership of bin BIN_ID to worker TARGET_WORKER"
-    echo "  spawn [BOOTSTRAP_SERVER]  -- spawn a new worker and add it to the cluster,"
+    echo "  moves STRATEGY BIN_ID TARGET_WORKER [BIN_ID TARGET_WORKER ...]"
+    echo "                            -- move several bins, STRATEGY is all-at-once (one configuration),"
+    echo "                               fluid (one configuration per bin) or batched:K (K bins per configuration)"
+    echo "  spawn [BOOTSTRAP_SERVER]  -- spawn a new worker process and add it to the cluster,"
     echo "                               BOOTSTRAP_SERVER is optional and defaults to worker 0"
+    echo "                               (or to the workers in turn with JOIN_POLICY=round-robin)"
+    echo "  preview                   -- show the bin moves of spawning a process from the current map"
+    echo "                               (the initial map with the moves sent so far), without sending anything"
+    echo "  status                    -- list the spawned worker processes that exited, with their last log lines,"
+    echo "                               and ask the workers for the bins they processed records of (printed by worker 0)"
+    echo "  quit                      -- kill the spawned worker processes and exit"
     echo "---------"
 }
 
@@ -42,15 +80,58 @@ while true; do
 
     case ${cmd} in
 
+        moves*)
+            tokens=(${cmd})
+            strategy=${tokens[1]}
+            case ${strategy} in
+                all-at-once) per_message=$(((${#tokens[@]}-2)/2));;
+                fluid) per_message=1;;
+                batched:*) per_message=${strategy#batched:};;
+                *) echo "Unknown strategy ${strategy}, expected all-at-once, fluid or batched:K"; continue;;
+            esac
+            # one message per configuration, applied by Megaphone one after the other
+            messages=""
+            message=""
+            count=0
+            for ((i=2; i+1<${#tokens[@]}; i+=2)); do
+                message="${message:+${message}, }move ${tokens[$i]} ${tokens[$((i+1))]}"
+                count=$((count+1))
+                if [[ ${count} -eq ${per_message} ]]; then
+                    messages="${messages}${message}"$'\n'
+                    message=""
+                    count=0
+                fi
+            done
+            if ! [[ -z "${message}" ]]; then
+                messages="${messages}${message}"$'\n'
+            fi
+            echo -n "${messages}"
+            echo -n "${messages}" | $KAFKA/bin/kafka-console-producer.sh --broker-list localhost:9092 --topic ${topic} || exit 1
+            echo " Commands sent"
+            init_map
+            for ((i=2; i+1<${#tokens[@]}; i+=2)); do
+                map[${tokens[$i]}]=${tokens[$((i+1))]}
+            done;;
+
         move*)
             echo "${cmd}" | $KAFKA/bin/kafka-console-producer.sh --broker-list localhost:9092 --topic ${topic} || exit 1
-            echo " Command sent";;
+            echo " Command sent"
+            tokens=(${cmd})
+            init_map
+            map[${tokens[1]}]=${tokens[2]};;
 
         spawn*)
             tokens=(${cmd})
-            bootstrap_server=${tokens[1]:-0}
+            default_server=0
+            if [[ "${JOIN_POLICY}" == "round-robin" ]]; then
+                default_server=$((joins % (p*w)))
+            fi
+            bootstrap_server=${tokens[1]:-${default_server}}
+            if ! [[ -z "${HOSTFILE}" ]]; then
+                p=`cargo run -q --bin hostfile -- add ${HOSTFILE}` || exit 1
+            fi
             echo "Spawning worker process ${p} using bootstrap_server ${bootstrap_server}:"
-            spawn_cmd="cargo run --bin ${bin} -- -n ${n} -w ${w} -p ${p} --join ${bootstrap_server} --nn $((${p}+1))"
+            spawn_cmd="cargo run --bin ${bin} -- -n ${n} -w ${w} -p ${p} --join ${bootstrap_server} --nn $((${p}+1)) ${host_args}"
             echo "   ${spawn_cmd}"
             if ! [[ -z "${TMUX}" ]]; then
                 CUR=`tmux display-message -p '#S:#W.#P'`
@@ -60,8 +141,10 @@ while true; do
                 tmux kill-window -t ${WIN} 2>/dev/null 1>/dev/null
                 tmux new-window -d -n ${WIN}
                 tmux send-keys -t ${SESS}:${WIN}.0 "${spawn_cmd}" Enter || exit 1
+                windows+=(${SESS}:${WIN})
             else
-                `${spawn_cmd} > ~/worker-${p} 2>&1 &`
+                ${spawn_cmd} > ~/worker-${p} 2>&1 &
+                pids[${p}]=$!
             fi
 
             # Important: send "Bootstrap" command so that the new workers will receive the updated megaphone routing table
@@ -69,6 +152,7 @@ while true; do
             for i in `seq 1 $((w-1))`; do
                 bootstrap_cmds="${bootstrap_cmds}, bootstrap ${bootstrap_server} $((p*w+i))"
             done
+            joins=$((joins+1))
             echo "Sending \"${bootstrap_cmds}\" control command"
             echo "${bootstrap_cmds}" | $KAFKA/bin/kafka-console-producer.sh --broker-list localhost:9092 --topic ${topic} || exit 1
             echo " Command sent"
@@ -76,6 +160,32 @@ while true; do
             p=$((${p}+1))
             ;;
 
+        preview*)
+            init_map
+            cargo run -q --bin plan -- --workers $((p*w)) --add ${w} --map $(IFS=,; echo "${map[*]}");;
+
+        status*)
+            for i in ${!pids[@]}; do
+                if ! kill -0 ${pids[$i]} 2>/dev/null; then
+                    wait ${pids[$i]}
+                    echo "Worker process ${i} exited with status $?, last lines of ~/worker-${i}:"
+                    tail -n 20 ~/worker-${i} | sed 's/^/  | /'
+                    unset 'pids[$i]'
+                fi
+            done
+            for i in ${!windows[@]}; do
+                if ! tmux has-session -t ${windows[$i]} 2>/dev/null; then
+                    echo "Window ${windows[$i]} was closed"
+                    unset 'windows[$i]'
+                fi
+            done
+            echo "status" | $KAFKA/bin/kafka-console-producer.sh --broker-list localhost:9092 --topic ${topic} || exit 1
+            echo " Status request sent"
+            ;;
+
+        quit*)
+            exit 0;;
+
         *)
             echo "Unrecognized command"
             help